@@ -1,5 +1,123 @@
 CREATE TABLE IF NOT EXISTS paragraphs (
-	reference TEXT PRIMARY KEY,
+	page_id TEXT PRIMARY KEY DEFAULT (lower(hex(randomblob(16)))),
+	reference TEXT NOT NULL UNIQUE,
 	text TEXT,
-	embedding BLOB 
-)
+	embedding BLOB,
+	model TEXT,
+	crawl_depth INTEGER,
+	word_count INTEGER,
+	retrieval_count INTEGER DEFAULT 0,
+	sentence_embeddings BLOB,
+	source_domain TEXT,
+	tags TEXT,
+	content_hash TEXT,
+	keywords_extracted TEXT,
+	url TEXT,
+	referrer_url TEXT,
+	page_rank REAL DEFAULT 0,
+	loaded_at TEXT,
+	batch_id TEXT,
+	compressed INTEGER DEFAULT 0,
+	format TEXT DEFAULT 'json',
+	summary TEXT,
+	prompt_hash TEXT,
+	summarization_confidence REAL,
+	simhash INTEGER,
+	version INTEGER DEFAULT 1,
+	sentence_count INTEGER,
+	language_code TEXT,
+	source_type TEXT CHECK (source_type IN ('url', 'file', 'manual')),
+	deleted_at TEXT
+);
+
+CREATE VIEW IF NOT EXISTS live_paragraphs AS SELECT * FROM paragraphs WHERE deleted_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS idx_paragraphs_domain ON paragraphs(source_domain);
+CREATE INDEX IF NOT EXISTS idx_paragraphs_loaded_at ON paragraphs(loaded_at);
+CREATE INDEX IF NOT EXISTS idx_paragraphs_retrieval_count ON paragraphs(retrieval_count);
+CREATE INDEX IF NOT EXISTS idx_paragraphs_simhash ON paragraphs(simhash);
+
+CREATE TABLE IF NOT EXISTS rate_limits (
+	endpoint TEXT PRIMARY KEY,
+	last_called_at INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS search_history (
+	id INTEGER PRIMARY KEY,
+	sentence TEXT UNIQUE,
+	count INTEGER DEFAULT 1,
+	last_queried TEXT
+);
+
+CREATE TABLE IF NOT EXISTS sentence_embeddings_cache (
+	sentence TEXT PRIMARY KEY,
+	embedding BLOB,
+	model TEXT,
+	created_at TEXT
+);
+
+CREATE TABLE IF NOT EXISTS corpus_snapshots (
+	snapshot_at TEXT PRIMARY KEY,
+	centroid BLOB,
+	paragraph_count INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS ab_tests (
+	id TEXT PRIMARY KEY,
+	question TEXT,
+	strategy_a_refs TEXT,
+	strategy_b_refs TEXT,
+	preferred TEXT,
+	created_at TEXT
+);
+
+CREATE TABLE IF NOT EXISTS prompts (
+	name TEXT PRIMARY KEY,
+	template TEXT
+);
+
+CREATE TABLE IF NOT EXISTS batches (
+	batch_id TEXT PRIMARY KEY,
+	created_at TEXT,
+	centroid BLOB
+);
+
+CREATE TABLE IF NOT EXISTS batch_paragraphs (
+	batch_id TEXT,
+	reference TEXT,
+	PRIMARY KEY (batch_id, reference)
+);
+
+CREATE TABLE IF NOT EXISTS paragraph_versions (
+	page_id TEXT NOT NULL,
+	version INTEGER NOT NULL,
+	text TEXT,
+	summary TEXT,
+	embedding BLOB,
+	compressed INTEGER,
+	format TEXT,
+	changed_at TEXT,
+	PRIMARY KEY (page_id, version)
+);
+
+CREATE TABLE IF NOT EXISTS faqs (
+	question TEXT NOT NULL,
+	answer TEXT NOT NULL,
+	source TEXT NOT NULL,
+	created_at TEXT DEFAULT (datetime('now'))
+);
+
+CREATE TABLE IF NOT EXISTS search_feedback (
+	query TEXT NOT NULL,
+	reference TEXT NOT NULL,
+	relevant INTEGER NOT NULL,
+	judged_at TEXT DEFAULT (datetime('now')),
+	PRIMARY KEY (query, reference)
+);
+
+CREATE TABLE IF NOT EXISTS corpus_version (
+	id INTEGER PRIMARY KEY CHECK (id = 1),
+	version INTEGER NOT NULL
+);
+
+INSERT OR IGNORE INTO corpus_version (id, version) VALUES (1, 0);
@@ -0,0 +1,193 @@
+//! Minimal HNSW (Hierarchical Navigable Small World) graph for approximate
+//! nearest-neighbour search over embeddings, using cosine distance.
+
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashSet};
+
+use crate::l2_normalized;
+
+/// Maximum neighbours per node on the upper layers.
+const M: usize = 16;
+/// Maximum neighbours per node on the bottom layer.
+const M0: usize = 2 * M;
+/// Size of the candidate list while inserting.
+const EF_CONSTRUCTION: usize = 100;
+
+pub struct Hnsw {
+    vectors: Vec<Vec<f32>>,
+    /// `links[node][layer]` holds the neighbours of `node` on `layer`.
+    links: Vec<Vec<Vec<usize>>>,
+    entry_point: Option<usize>,
+    max_layer: usize,
+    rng_state: u64,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    node: usize,
+}
+
+impl Eq for Candidate {}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .total_cmp(&other.distance)
+            .then(self.node.cmp(&other.node))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Hnsw {
+    /// Builds the graph over `vectors`; node ids are their indices.
+    pub fn build(vectors: Vec<Vec<f32>>) -> Self {
+        let mut index = Hnsw {
+            vectors: Vec::with_capacity(vectors.len()),
+            links: Vec::with_capacity(vectors.len()),
+            entry_point: None,
+            max_layer: 0,
+            // Fixed seed, so rebuilding over the same data yields the same graph.
+            rng_state: 0x9E37_79B9_7F4A_7C15,
+        };
+        for vector in vectors {
+            index.insert(vector);
+        }
+        index
+    }
+
+    /// Returns up to `k` `(node, cosine similarity)` pairs, most similar first.
+    pub fn search(&self, query: &[f32], k: usize, ef: usize) -> Vec<(usize, f32)> {
+        let mut entry = match self.entry_point {
+            Some(entry) => entry,
+            None => return vec![],
+        };
+        let query = l2_normalized(query);
+        for layer in (1..=self.max_layer).rev() {
+            entry = self.search_layer(&query, entry, 1, layer)[0].node;
+        }
+        let mut found = self.search_layer(&query, entry, ef.max(k), 0);
+        found.truncate(k);
+        found
+            .into_iter()
+            .map(|c| (c.node, 1.0 - c.distance))
+            .collect()
+    }
+
+    fn insert(&mut self, vector: Vec<f32>) {
+        let node = self.vectors.len();
+        let level = self.random_level();
+        self.vectors.push(l2_normalized(&vector));
+        self.links.push(vec![Vec::new(); level + 1]);
+
+        let mut entry = match self.entry_point {
+            Some(entry) => entry,
+            None => {
+                self.entry_point = Some(node);
+                self.max_layer = level;
+                return;
+            }
+        };
+
+        for layer in (level + 1..=self.max_layer).rev() {
+            entry = self.search_layer(&self.vectors[node], entry, 1, layer)[0].node;
+        }
+        for layer in (0..=level.min(self.max_layer)).rev() {
+            let candidates = self.search_layer(&self.vectors[node], entry, EF_CONSTRUCTION, layer);
+            let max_links = if layer == 0 { M0 } else { M };
+            let neighbours: Vec<usize> =
+                candidates.iter().take(max_links).map(|c| c.node).collect();
+            for &neighbour in &neighbours {
+                self.links[neighbour][layer].push(node);
+                if self.links[neighbour][layer].len() > max_links {
+                    self.prune(neighbour, layer, max_links);
+                }
+            }
+            self.links[node][layer] = neighbours;
+            entry = candidates[0].node;
+        }
+
+        if level > self.max_layer {
+            self.max_layer = level;
+            self.entry_point = Some(node);
+        }
+    }
+
+    /// Greedy best-first search on one layer; returns the `ef` closest nodes
+    /// found, closest first. Never empty, as it always contains `entry`.
+    fn search_layer(&self, query: &[f32], entry: usize, ef: usize, layer: usize) -> Vec<Candidate> {
+        let first = Candidate {
+            distance: self.distance(query, entry),
+            node: entry,
+        };
+        let mut visited = HashSet::from([entry]);
+        let mut candidates = BinaryHeap::from([Reverse(first)]);
+        let mut found = BinaryHeap::from([first]);
+
+        while let Some(Reverse(current)) = candidates.pop() {
+            let furthest = found.peek().map_or(f32::MAX, |c| c.distance);
+            if current.distance > furthest && found.len() >= ef {
+                break;
+            }
+            for &neighbour in &self.links[current.node][layer] {
+                if !visited.insert(neighbour) {
+                    continue;
+                }
+                let candidate = Candidate {
+                    distance: self.distance(query, neighbour),
+                    node: neighbour,
+                };
+                let furthest = found.peek().map_or(f32::MAX, |c| c.distance);
+                if found.len() < ef || candidate.distance < furthest {
+                    candidates.push(Reverse(candidate));
+                    found.push(candidate);
+                    if found.len() > ef {
+                        found.pop();
+                    }
+                }
+            }
+        }
+
+        found.into_sorted_vec()
+    }
+
+    /// Keeps only the `max_links` closest neighbours of `node` on `layer`.
+    fn prune(&mut self, node: usize, layer: usize, max_links: usize) {
+        let mut neighbours: Vec<Candidate> = self.links[node][layer]
+            .iter()
+            .map(|&n| Candidate {
+                distance: self.distance(&self.vectors[node], n),
+                node: n,
+            })
+            .collect();
+        neighbours.sort();
+        neighbours.truncate(max_links);
+        self.links[node][layer] = neighbours.into_iter().map(|c| c.node).collect();
+    }
+
+    fn distance(&self, query: &[f32], node: usize) -> f32 {
+        let dot_product = query
+            .iter()
+            .zip(self.vectors[node].iter())
+            .map(|(x, y)| x * y)
+            .sum::<f32>();
+        1.0 - dot_product
+    }
+
+    /// Draws a layer from the exponentially decaying distribution used by HNSW.
+    fn random_level(&mut self) -> usize {
+        // xorshift64*
+        self.rng_state ^= self.rng_state >> 12;
+        self.rng_state ^= self.rng_state << 25;
+        self.rng_state ^= self.rng_state >> 27;
+        let random = self.rng_state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        let uniform = ((random >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let level_multiplier = 1.0 / (M as f64).ln();
+        (-uniform.ln() * level_multiplier).floor() as usize
+    }
+}
@@ -1,30 +1,202 @@
 use anyhow::{Context, Result};
-use log::{error, info, trace, LevelFilter::Info};
-use serde::{Deserialize, Serialize};
+use log::{
+    error, info, trace, warn,
+    LevelFilter::{Info, Trace},
+};
+use serde::{ser::SerializeSeq, Deserialize, Serialize, Serializer as _};
 use serde_json::*;
 use spin_sdk::{
     http::{Params, Request, Response},
     http_component, http_router,
     llm::{
-        generate_embeddings, EmbeddingModel::AllMiniLmL6V2, EmbeddingsResult,
-        InferencingModel::Llama2Chat,
+        generate_embeddings,
+        EmbeddingModel::{self, AllMiniLmL6V2},
+        EmbeddingsResult,
+        InferencingModel::{self, Llama2Chat},
+        InferencingParams, InferencingResult,
     },
     sqlite::{self, Connection, ValueResult},
 };
+use std::collections::{HashMap, HashSet};
+
+#[cfg(feature = "hnsw")]
+mod hnsw;
+mod sentence_splitter;
+mod vector_math;
+
+/// Name of the model used to embed paragraphs, stored alongside each embedding.
+const EMBEDDING_MODEL: &str = "all-minilm-l6-v2";
+/// Dimension of the vectors produced by `EMBEDDING_MODEL`.
+const EMBEDDING_DIM: usize = 384;
+/// Number of keywords stored in `keywords_extracted` for each paragraph.
+const KEYWORDS_PER_PARAGRAPH: usize = 10;
+/// Language code of the stored pages, which `auto_translate` searches are
+/// translated to.
+const CORPUS_LANGUAGE: &str = "en";
+/// zstd level of compressed embeddings, favoring speed over ratio.
+#[cfg(feature = "zstd")]
+const ZSTD_LEVEL: i32 = 3;
+/// Length of summaries, in sentences, unless a request asks for another one.
+const SUMMARY_SENTENCES: usize = 3;
+/// Shortest text, in characters, that `POST /embeddings/summarize` accepts.
+const MIN_TEXT_LENGTH: usize = 50;
+/// Largest body accepted by `POST /embeddings/embed`.
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+/// Number of texts sent to the embedding model in one call.
+const EMBEDDING_BATCH_SIZE: usize = 32;
+/// Most bits in which the SimHashes of two near-duplicate texts may differ.
+const SIMHASH_MAX_DISTANCE: u32 = 3;
+/// Rephrasings generated for a query expanded with `expand=true`.
+const DEFAULT_EXPANSIONS: usize = 3;
+
+/// Approximate nearest-neighbour index over all stored paragraphs. It is built
+/// on the first search of a component instance and rebuilt lazily once a write
+/// of any instance has changed the `corpus_version`. As Spin 1 creates an
+/// instance per request, it is only reused on hosts that keep instances alive.
+#[cfg(feature = "hnsw")]
+struct SearchIndex {
+    records: Vec<ParagraphRecord>,
+    graph: hnsw::Hnsw,
+    /// `corpus_version` the records were loaded at.
+    corpus_version: i64,
+}
+
+/// All paragraphs with their embeddings, loaded by `POST /embeddings/warm` so
+/// that unfiltered searches of this component instance skip SQLite. Cleared
+/// whenever paragraphs change, and ignored once another instance has changed
+/// them. Spin 1 creates a new instance for every request, so this only outlives
+/// the request on hosts that reuse instances.
+static WARM_RECORDS: std::sync::Mutex<Option<WarmRecords>> = std::sync::Mutex::new(None);
+
+struct WarmRecords {
+    records: Vec<ParagraphRecord>,
+    /// `corpus_version` the records were loaded at.
+    corpus_version: i64,
+}
+
+/// `X-Correlation-Id` of the request being handled, logged with each LLM call
+/// so that host-side LLM logs can be traced back to the request.
+static CORRELATION_ID: std::sync::Mutex<Option<String>> = std::sync::Mutex::new(None);
+
+#[cfg(feature = "hnsw")]
+static SEARCH_INDEX: std::sync::Mutex<Option<SearchIndex>> = std::sync::Mutex::new(None);
 
 #[http_component]
 fn handle_request(req: Request) -> Result<Response> {
-    env_logger::builder().filter_level(Info).init();
+    // Load balancer heartbeats skip logging, configuration and the database.
+    if *req.method() == http::Method::GET && req.uri().path() == "/embeddings/ping" {
+        return ping();
+    }
+    let timer = RequestTimer::start();
+    // The embedding statistics are logged at trace level, so show them.
+    let level = if cfg!(feature = "debug_embeddings") {
+        Trace
+    } else {
+        Info
+    };
+    // A host reusing the component instance has the logger set up already.
+    let _ = env_logger::builder().filter_level(level).try_init();
 
     info!(
         "Received {} request at {}",
         req.method().to_string(),
         req.uri().to_string()
     );
+    let correlation_id = req
+        .headers()
+        .get("X-Correlation-Id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+    match CORRELATION_ID.lock() {
+        // Instances may be reused, so this also clears the previous request's id.
+        Ok(mut id) => *id = correlation_id,
+        Err(_) => error!("Correlation id lock is poisoned"),
+    }
+    if is_read_only()? && is_write_request(req.method(), req.uri().path()) {
+        return read_only_response();
+    }
+
+    // Fail fast on misconfigured variables instead of on the first LLM call.
+    inference_params().context("Invalid inference configuration")?;
+    ensure_schema().context("Failed to migrate the database schema")?;
 
     let router = http_router! {
         GET "/embeddings" => get_paragraphs,
+        GET "/embeddings/by-url" => get_paragraph_by_url,
+        GET "/embeddings/by-id/:page_id" => get_paragraph_by_id,
+        GET "/embeddings/orphans" => get_orphan_paragraphs,
+        GET "/embeddings/coverage" => get_domain_coverage,
+        GET "/embeddings/top-domains" => get_top_domains,
+        GET "/embeddings/anomalies" => get_anomalies,
+        GET "/embeddings/snapshots" => get_embeddings_snapshots,
+        GET "/embeddings/diversity-score" => get_diversity_score,
+        GET "/embeddings/drift" => get_corpus_drift,
+        GET "/embeddings/prompts" => get_prompts,
+        GET "/embeddings/batches" => get_batches,
+        GET "/embeddings/compare-batches" => compare_batches,
+        GET "/embeddings/query-plans" => get_query_plans,
+        GET "/embeddings/backfill-needed" => get_backfill_needed,
+        GET "/embeddings/ab-test/:id/result" => get_ab_test_result,
+        GET "/embeddings/search/history" => get_search_history,
+        GET "/embeddings/faq" => get_faqs,
+        GET "/embeddings/text-duplicates" => get_text_duplicates,
+        GET "/embeddings/feedback/metrics" => get_feedback_metrics,
+        GET "/embeddings/hot-queries" => get_hot_queries,
+        GET "/embeddings/search/preview" => preview_similar_paragraphs,
+        GET "/embeddings/search/suggest-related" => suggest_related_keywords,
+        GET "/embeddings/similar-to-query" => get_similar_excluding,
+        GET "/embeddings/neighbors" => get_neighbors,
+        GET "/embeddings/by-tag/:tag" => get_paragraphs_by_tag,
+        GET "/embeddings/embedding/:reference" => get_paragraph_embedding,
+        GET "/embeddings/:reference/timeline" => get_paragraph_timeline,
+        GET "/embeddings/:reference/timeline/:version" => get_paragraph_timeline_version,
+        GET "/embeddings/:reference" => get_paragraph_record,
         POST "/embeddings" => create_paragraphs_records,
+        POST "/embeddings/compare" => compare_texts,
+        POST "/embeddings/classify" => classify_paragraph,
+        POST "/embeddings/embed" => embed_texts,
+        POST "/embeddings/ingest-url" => ingest_url,
+        POST "/embeddings/ingest-sitemap" => ingest_sitemap,
+        POST "/embeddings/similarity-matrix" => get_similarity_matrix,
+        POST "/embeddings/segment" => segment_text,
+        POST "/embeddings/search/explain" => explain_similar_paragraphs,
+        POST "/embeddings/search/explain-cosine" => explain_cosine,
+        POST "/embeddings/search/rerank" => rerank_similar_paragraphs,
+        POST "/embeddings/search/aggregate" => aggregate_similar_paragraphs,
+        POST "/embeddings/search/by-vector" => search_by_vector,
+        POST "/embeddings/search/pipeline" => run_search_pipeline,
+        POST "/embeddings/cross-reference" => cross_reference_domains,
+        POST "/embeddings/vector-math" => compute_vector_math,
+        POST "/embeddings/rerank-by-date" => rerank_by_date,
+        POST "/embeddings/explain-corpus" => explain_corpus,
+        POST "/embeddings/quiz" => generate_quiz,
+        POST "/embeddings/generate-faq" => generate_faqs,
+        POST "/embeddings/ab-test" => run_ab_test,
+        POST "/embeddings/ab-test/:id/result" => record_ab_test_preference,
+        POST "/embeddings/translate" => translate_and_search,
+        POST "/embeddings/hypothetical-doc" => search_hypothetical_doc,
+        POST "/embeddings/query-expansion" => search_expanded_query,
+        POST "/embeddings/summarize" => summarize,
+        POST "/embeddings/feedback" => record_feedback,
+        POST "/embeddings/score-dataset" => score_dataset,
+        POST "/embeddings/vacuum" => vacuum_database,
+        POST "/embeddings/warm" => warm_paragraphs,
+        POST "/embeddings/snapshot" => create_corpus_snapshot,
+        POST "/embeddings/snapshot-embeddings" => save_embeddings_snapshot,
+        POST "/embeddings/restore/:name" => restore_embeddings_snapshot,
+        POST "/embeddings/compute-pagerank" => compute_page_rank,
+        POST "/embeddings/anchor-graph" => get_anchor_graph,
+        POST "/embeddings/anchor-search" => search_from_anchor,
+        POST "/embeddings/prompts" => save_prompt,
+        POST "/embeddings/rollback" => rollback_batch,
+        POST "/embeddings/compress" => compress_embeddings,
+        POST "/embeddings/batch-delete-by-domain" => batch_delete_by_domain,
+        POST "/embeddings/backfill-summaries" => backfill_summaries,
+        PUT "/embeddings/:reference/embedding" => put_paragraph_embedding,
+        PATCH "/embeddings/:reference/text" => patch_paragraph_text,
+        DELETE "/embeddings/query-cache" => clear_query_cache,
+        DELETE "/embeddings/domain/:domain" => delete_domain,
+        DELETE "/embeddings/text-duplicates" => delete_text_duplicates,
         DELETE "/embeddings/:reference" => delete_paragraph_record,
         _ "/*" => |_req, _params| {
             Ok(http::Response::builder()
@@ -34,49 +206,218 @@ fn handle_request(req: Request) -> Result<Response> {
         }
     };
 
-    router.handle(req)
+    timer.finish(router.handle(req))
 }
 
-fn get_paragraphs(req: Request, _params: Params) -> Result<Response> {
-    match req.uri().query() {
-        Some(query) => {
-            let query: Query = serde_qs::from_str(query)?;
-            let result_set = get_similar_paragraphs(&query.sentence)?;
+/// `POST` routes that only read stored data, apart from the search history,
+/// retrieval counts and caches that searches update, and so stay enabled with
+/// `read_only`. It disables every other `POST` route and all `PUT`, `PATCH` and
+/// `DELETE` routes, so new routes count as writes until they're listed here.
+const READ_ONLY_POST_ROUTES: &[&str] = &[
+    "/embeddings/compare",
+    "/embeddings/classify",
+    "/embeddings/embed",
+    "/embeddings/similarity-matrix",
+    "/embeddings/search/explain",
+    "/embeddings/search/explain-cosine",
+    "/embeddings/search/rerank",
+    "/embeddings/search/aggregate",
+    "/embeddings/search/by-vector",
+    "/embeddings/search/pipeline",
+    "/embeddings/cross-reference",
+    "/embeddings/vector-math",
+    "/embeddings/rerank-by-date",
+    "/embeddings/quiz",
+    "/embeddings/translate",
+    "/embeddings/hypothetical-doc",
+    "/embeddings/query-expansion",
+    "/embeddings/score-dataset",
+    "/embeddings/anchor-graph",
+    "/embeddings/anchor-search",
+];
 
-            Ok(http::Response::builder()
-                .status(http::StatusCode::OK)
-                .header("Content-Type", "application/json")
-                .body(Some(serde_json::to_vec(&result_set)?.into()))?)
+static READ_ONLY: std::sync::OnceLock<bool> = std::sync::OnceLock::new();
+
+/// The `read_only` variable, read once per instance.
+fn is_read_only() -> Result<bool> {
+    if let Some(read_only) = READ_ONLY.get() {
+        return Ok(*read_only);
+    }
+    let read_only = variable("read_only", false)?;
+    Ok(*READ_ONLY.get_or_init(|| read_only))
+}
+
+fn is_write_request(method: &http::Method, path: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    if *method == http::Method::POST {
+        !READ_ONLY_POST_ROUTES.contains(&path)
+    } else {
+        *method == http::Method::PUT
+            || *method == http::Method::PATCH
+            || *method == http::Method::DELETE
+    }
+}
+
+fn read_only_response() -> Result<Response> {
+    json_response(
+        http::StatusCode::METHOD_NOT_ALLOWED,
+        &ErrorBody {
+            code: "read_only",
+            message: "This instance is in read-only mode",
+        },
+    )
+}
+
+/// Liveness probe, answered before any other work is done.
+fn ping() -> Result<Response> {
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .body(Some(b"pong".as_slice().into()))?)
+}
+
+/// Adds an `X-Response-Time` header to responses when built with the `timing`
+/// feature, and does nothing otherwise, as not every WASM target has a clock
+/// backing `std::time::Instant`.
+struct RequestTimer {
+    #[cfg(feature = "timing")]
+    started: std::time::Instant,
+}
+
+impl RequestTimer {
+    fn start() -> Self {
+        RequestTimer {
+            #[cfg(feature = "timing")]
+            started: std::time::Instant::now(),
         }
-        None => {
-            let query = "SELECT * FROM paragraphs";
-            let conn = Connection::open_default()?;
-            let all_paragraphs: Vec<Paragraph> = match conn
-                .execute(query, &[])?
-                .rows()
-                .map(|row| -> anyhow::Result<Paragraph> { row.try_into() })
-                .collect::<anyhow::Result<Vec<Paragraph>>>()
-            {
-                Ok(p) => {
-                    trace!("All paragraphs: {:?}", p);
-                    p
+    }
+
+    #[cfg(feature = "timing")]
+    fn finish(self, response: Result<Response>) -> Result<Response> {
+        let mut response = response?;
+        let elapsed = format!("{}ms", self.started.elapsed().as_millis());
+        response.headers_mut().insert(
+            "X-Response-Time",
+            http::header::HeaderValue::from_str(&elapsed)?,
+        );
+        Ok(response)
+    }
+
+    #[cfg(not(feature = "timing"))]
+    fn finish(self, response: Result<Response>) -> Result<Response> {
+        response
+    }
+}
+
+fn get_paragraphs(req: Request, _params: Params) -> Result<Response> {
+    let format = match ResponseFormat::from_accept(&req) {
+        Some(format) => format,
+        None => return unsupported_media_type(),
+    };
+
+    let query_string = req.uri().query().unwrap_or_default();
+    let list_query: ListFilterQuery = serde_qs::from_str(query_string)?;
+    match list_query.sentence {
+        Some(_) => {
+            let query: Query = serde_qs::from_str(query_string)?;
+            let ranking = RankingOptions {
+                snippets: true,
+                ..query.ranking()
+            };
+            if let Err(message) = ranking.validate() {
+                return bad_request(message);
+            }
+            let sentence = if query.auto_translate {
+                translate_sentence(&query.sentence, CORPUS_LANGUAGE)?
+            } else {
+                query.sentence.clone()
+            };
+            let result_set = match query.metric {
+                Some(SearchMetric::Bm25) => {
+                    if !FTS5_AVAILABLE.load(std::sync::atomic::Ordering::Relaxed) {
+                        return bad_request("metric=bm25 needs SQLite built with FTS5");
+                    }
+                    search_bm25(&sentence, &query.filter())?
                 }
-                Err(err) => {
-                    error!("Error getting paragraphs from db: {:?}", err);
-                    return Ok(http::Response::builder()
-                        .status(http::StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(Some("Failed to get records".into()))?);
+                Some(SearchMetric::Cosine) | None => {
+                    get_similar_paragraphs(&sentence, &query.filter(), &ranking)?
                 }
             };
+            record_retrievals(&result_set.results);
+            record_search(&sentence);
 
-            Ok(http::Response::builder()
-                .status(http::StatusCode::OK)
-                .body(Some(serde_json::to_string(&all_paragraphs)?.into()))?)
+            formatted_response(format, http::StatusCode::OK, &result_set)
+        }
+        None => {
+            let mut query = String::from("SELECT * FROM live_paragraphs");
+            let mut conditions: Vec<&str> = vec![];
+            let mut query_params: Vec<sqlite::ValueParam> = vec![];
+            if let Some(min_sentences) = list_query.min_sentences {
+                conditions.push("(sentence_count IS NULL OR sentence_count >= ?)");
+                query_params.push(sqlite::ValueParam::Integer(min_sentences));
+            }
+            if let Some(source_type) = list_query.source_type {
+                conditions.push("source_type = ?");
+                query_params.push(sqlite::ValueParam::Text(source_type.as_str()));
+            }
+            if !conditions.is_empty() {
+                query.push_str(" WHERE ");
+                query.push_str(&conditions.join(" AND "));
+            }
+            let conn = Connection::open_default()?;
+            if list_query.include_embeddings {
+                let records = conn
+                    .execute(&query, &query_params)?
+                    .rows()
+                    .map(ParagraphRecord::try_from)
+                    .collect::<Result<Vec<ParagraphRecord>>>()?;
+                let records: Vec<WithEmbedding> = records.iter().map(WithEmbedding::from).collect();
+                return formatted_response(format, http::StatusCode::OK, &records);
+            }
+            let result = conn.execute(&query, &query_params)?;
+            let paragraphs = result.rows().map(Paragraph::try_from);
+            if let ResponseFormat::Json = format {
+                // Streamed, as the whole corpus would otherwise be held as
+                // paragraphs and again as JSON values.
+                return match json_array_response(http::StatusCode::OK, paragraphs) {
+                    Ok(response) => Ok(response),
+                    Err(err) => {
+                        error!("Error getting paragraphs from db: {:?}", err);
+                        Ok(http::Response::builder()
+                            .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(Some("Failed to get records".into()))?)
+                    }
+                };
+            }
+            let all_paragraphs: Vec<Paragraph> =
+                match paragraphs.collect::<Result<Vec<Paragraph>>>() {
+                    Ok(p) => {
+                        trace!("All paragraphs: {:?}", p);
+                        p
+                    }
+                    Err(err) => {
+                        error!("Error getting paragraphs from db: {:?}", err);
+                        return Ok(http::Response::builder()
+                            .status(http::StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(Some("Failed to get records".into()))?);
+                    }
+                };
+
+            formatted_response(format, http::StatusCode::OK, &all_paragraphs)
         }
     }
 }
 
 fn create_paragraphs_records(req: Request, _params: Params) -> Result<Response> {
+    let params: IngestParams = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(p) => p,
+        Err(err) => {
+            error!("Failed to parse ingest parameters: {:?}", err);
+            return bad_request("Invalid ingest parameters");
+        }
+    };
+    if !(1..=10).contains(&params.summary_sentences) {
+        return bad_request("summary_sentences must be between 1 and 10");
+    }
     let paragraphs: Vec<Page> = match serde_json::from_slice(
         req.body()
             .as_deref()
@@ -90,40 +431,13 @@ fn create_paragraphs_records(req: Request, _params: Params) -> Result<Response>
         }
     };
 
-    let text: Vec<&str> = paragraphs.iter().map(|e| e.text.as_str()).collect();
-    let summaries: Vec<String> = text
-        .iter()
-        .map(|e| match summarize_text(e) {
-            Ok(summary) => summary,
-            Err(err) => {
-                error!("Failed to summarize text: {:?}", err);
-                String::new() // return an empty string or handle the error appropriately
-            }
-        })
-        .collect();
-
-    let summary: Vec<&str> = summaries.iter().map(AsRef::as_ref).collect();
-
-    let embedding_result: EmbeddingsResult = match generate_embeddings(AllMiniLmL6V2, &summary) {
-        Ok(er) => {
-            trace!("Generated embeddings: {:?}", er);
-            er
-        }
-        Err(err) => {
-            error!(
-                "Failed to generate embeddings when calling Spin llm: {:?}",
-                err
-            );
-            return Err(err.into());
-        }
-    };
-
-    match store_paragraph_records(paragraphs, embedding_result) {
-        Ok(num_rec) => {
-            info!("Generated {:?} embeddings", num_rec);
-            Ok(http::Response::builder()
-                .status(http::StatusCode::CREATED)
-                .body(Some(format!("Stored {:?} records", num_rec).into()))?)
+    match ingest_pages(paragraphs, &params) {
+        Ok(result) => {
+            let status = match result.stored {
+                0 => http::StatusCode::OK,
+                _ => http::StatusCode::CREATED,
+            };
+            json_response(status, &result)
         }
         Err(err) => {
             error!("Failed to store records: {:?}", err);
@@ -134,232 +448,7656 @@ fn create_paragraphs_records(req: Request, _params: Params) -> Result<Response>
     }
 }
 
-fn summarize_text(_text: &str) -> Result<String> {
-    const PROMPT: &str = r#"<s>[INST]<<SYS>>You are a professional summarization assistant. Take the input text and return a summary in three sentences. Please keep your responses concise, up to three sentences..<</SYS>>Please summarize following text: {SENTENCE} [/INST]"#;
+/// Embeds and stores the pages whose text changed since they were stored.
+fn ingest_pages(paragraphs: Vec<Page>, params: &IngestParams) -> Result<IngestResult> {
+    // Skip the LLM entirely for pages whose text hasn't changed since they
+    // were last stored.
+    let conn = Connection::open_default()?;
+    let (unchanged, paragraphs): (Vec<Page>, Vec<Page>) = paragraphs.into_iter().partition(|p| {
+        match is_unchanged(&conn, &p.metadata.title, &p.text) {
+            Ok(unchanged) => unchanged,
+            Err(err) => {
+                error!("Failed to check content hash: {:?}", err);
+                false
+            }
+        }
+    });
+    // Near-duplicates of pages stored under another reference, such as mirrors
+    // or syndicated copies, are skipped as well.
+    let mut known = load_simhashes(&conn)?;
+    let (paragraphs, duplicates): (Vec<Page>, Vec<Page>) = paragraphs.into_iter().partition(|p| {
+        let hash = simhash(&p.text);
+        let duplicate_of = known.iter().find(|(reference, known_hash)| {
+            *reference != p.metadata.title
+                && (known_hash ^ hash).count_ones() <= SIMHASH_MAX_DISTANCE
+        });
+        match duplicate_of {
+            Some((reference, _)) => {
+                info!(
+                    "Skipping {}, a near-duplicate of {}",
+                    p.metadata.title, reference
+                );
+                false
+            }
+            None => {
+                known.push((p.metadata.title.clone(), hash));
+                true
+            }
+        }
+    });
+    if paragraphs.is_empty() {
+        info!(
+            "All {} records are unchanged or duplicates",
+            unchanged.len() + duplicates.len()
+        );
+        if params.warm {
+            warm_after_ingest();
+        }
+        return Ok(IngestResult {
+            stored: 0,
+            unchanged: unchanged.len(),
+            skipped_duplicates: duplicates.len(),
+            failed: 0,
+            errors: vec![],
+            batch_id: None,
+        });
+    }
+
+    if params.skip_summarize {
+        warn!(
+            "Embedding the raw text of {} pages, the embedding model may truncate long pages",
+            paragraphs.len()
+        );
+    }
+    let batch_id = create_batch(&conn)?;
+    let corpus = DocumentFrequencies::load(&conn)?;
+    // One page at a time, so a failing LLM call only loses that page.
+    let mut members: Vec<String> = vec![];
+    let mut errors: Vec<IngestError> = vec![];
+    for page in &paragraphs {
+        let stored = embed_page(page, params).and_then(|(embedding, summary)| {
+            store_paragraph_record(&conn, &corpus, page, embedding, summary, &batch_id)
+        });
+        match stored {
+            Ok(()) => members.push(page.metadata.title.clone()),
+            Err(err) => {
+                error!("Failed to ingest {}: {:?}", page.metadata.title, err);
+                errors.push(IngestError {
+                    reference: page.metadata.title.clone(),
+                    error: format!("{:#}", err),
+                });
+            }
+        }
+    }
+    invalidate_search_index();
 
-    let inferencing_result =
-        spin_sdk::llm::infer(Llama2Chat, &PROMPT.replace("{SENTENCE}", _text))?;
-    Ok(inferencing_result.text)
+    let stored = members.len();
+    members.extend(unchanged.iter().map(|p| p.metadata.title.clone()));
+    if let Err(err) = record_batch_members(&conn, &batch_id, &members) {
+        error!("Failed to record members of batch {}: {:?}", batch_id, err);
+    }
+    info!(
+        "Stored {} and failed to store {} pages in batch {}",
+        stored,
+        errors.len(),
+        batch_id
+    );
+    if params.warm {
+        warm_after_ingest();
+    }
+    Ok(IngestResult {
+        stored,
+        unchanged: unchanged.len(),
+        skipped_duplicates: duplicates.len(),
+        failed: errors.len(),
+        errors,
+        batch_id: Some(batch_id),
+    })
 }
 
-fn store_paragraph_records(
-    paragraphs: Vec<Page>,
-    embedding_result: EmbeddingsResult,
-) -> Result<usize> {
-    let conn = Connection::open_default()?;
+/// Embeds the LLM summary of a page, or its raw text with `skip_summarize`.
+fn embed_page(
+    page: &Page,
+    params: &IngestParams,
+) -> Result<(Vec<f32>, Option<SummarizationResult>)> {
+    if params.skip_summarize {
+        let record = ParagraphRecord::try_from(page)?;
+        return Ok((record.embedding, None));
+    }
+
+    let summary = match summarize_text(&page.text, params.summary_sentences) {
+        Ok(summary) if !summary.summary.is_empty() => summary,
+        Ok(_) => anyhow::bail!("LLM returned an empty summary for {}", page.metadata.title),
+        Err(err) => {
+            error!("Failed to summarize text: {:?}", err);
+            return Err(err.context(format!("Failed to summarize {}", page.metadata.title)));
+        }
+    };
+    let embedding_result: EmbeddingsResult =
+        match generate_normalized_embeddings(&[summary.summary.as_str()]) {
+            Ok(er) => {
+                trace!("Generated embeddings: {:?}", er);
+                er
+            }
+            Err(err) => {
+                error!(
+                    "Failed to generate embeddings when calling Spin llm: {:?}",
+                    err
+                );
+                return Err(err);
+            }
+        };
+    let embedding = embedding_result
+        .embeddings
+        .into_iter()
+        .next()
+        .with_context(|| format!("LLM returned no embedding for {}", page.metadata.title))?;
+    Ok((embedding, Some(summary)))
+}
 
-    for (e, res) in paragraphs.iter().zip(embedding_result.embeddings) {
-        let vec = json!(res.clone());
-        let blob = serde_json::to_vec(&vec)?;
+/// Records a new ingest batch, so that its paragraphs can be rolled back
+/// together. Returns the batch ID.
+fn create_batch(conn: &Connection) -> Result<String> {
+    let batch_id = conn
+        .execute(
+            "INSERT INTO batches (batch_id, created_at) VALUES (lower(hex(randomblob(16))), datetime('now')) RETURNING batch_id",
+            &[],
+        )?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<&str>("batch_id").map(str::to_owned))
+        .context("Batch was not stored")?;
+    Ok(batch_id)
+}
 
+/// Records the pages a batch saw, stored or unchanged, and the centroid of
+/// their embeddings, so that two crawl runs can be compared later with
+/// `GET /embeddings/compare-batches`.
+fn record_batch_members(conn: &Connection, batch_id: &str, references: &[String]) -> Result<()> {
+    for reference in references {
         let query_params = [
-            sqlite::ValueParam::Text(&e.metadata.title.as_str()),
-            sqlite::ValueParam::Text(e.text.as_str()),
-            sqlite::ValueParam::Blob(blob.as_slice()),
+            sqlite::ValueParam::Text(batch_id),
+            sqlite::ValueParam::Text(reference),
         ];
-
-        let _ = conn.execute(
-            "INSERT INTO paragraphs ('reference', 'text', 'embedding') VALUES (?, ?, ?);",
+        conn.execute(
+            "INSERT OR IGNORE INTO batch_paragraphs (batch_id, reference) VALUES (?, ?)",
             &query_params,
-        );
+        )?;
     }
 
-    Ok(paragraphs.len())
+    let query_params = [sqlite::ValueParam::Text(batch_id)];
+    let embeddings = conn
+        .execute(
+            "SELECT p.embedding, p.compressed, p.format FROM live_paragraphs p
+            JOIN batch_paragraphs b ON b.reference = p.reference WHERE b.batch_id = ?",
+            &query_params,
+        )?
+        .rows()
+        .map(|row| embedding_from_row(&row))
+        .collect::<Result<Vec<Vec<f32>>>>()?;
+    if embeddings.is_empty() {
+        return Ok(());
+    }
+    let blob = embedding_to_blob(&centroid(embeddings.iter().map(Vec::as_slice)))?;
+    let query_params = [
+        sqlite::ValueParam::Blob(blob.as_slice()),
+        sqlite::ValueParam::Text(batch_id),
+    ];
+    conn.execute(
+        "UPDATE batches SET centroid = ? WHERE batch_id = ?",
+        &query_params,
+    )?;
+    Ok(())
 }
 
-fn delete_paragraph_record(_req: Request, params: Params) -> Result<Response> {
-    let status = match params.get("reference") {
-        Some(reference) => {
-            let query_params = [sqlite::ValueParam::Text(reference)];
-            let conn = Connection::open_default()?;
-            let _ = conn.execute(
-                "DELETE FROM paragraphs WHERE reference = (?)",
-                &query_params,
-            );
-            info!("Deleted one record");
-            http::StatusCode::OK
+/// Compares the pages two ingest batches saw and the centroids of their
+/// embeddings, e.g. to detect content drift between two crawl runs.
+fn compare_batches(req: Request, _params: Params) -> Result<Response> {
+    let query: CompareBatchesQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default())
+    {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse compare-batches query: {:?}", err);
+            return bad_request("Missing batch_a or batch_b query parameter");
         }
-        None => http::StatusCode::NOT_FOUND,
     };
 
-    Ok(http::Response::builder().status(status).body(None)?)
+    let conn = Connection::open_default()?;
+    let (Some(a), Some(b)) = (
+        load_batch(&conn, &query.batch_a)?,
+        load_batch(&conn, &query.batch_b)?,
+    ) else {
+        return not_found();
+    };
+
+    json_response(
+        http::StatusCode::OK,
+        &BatchComparison {
+            only_in_a: a.members.difference(&b.members).count(),
+            only_in_b: b.members.difference(&a.members).count(),
+            in_both: a.members.intersection(&b.members).count(),
+            centroid_similarity: a
+                .centroid
+                .zip(b.centroid)
+                .map(|(a, b)| cosine_similarity(&a, &b)),
+        },
+    )
 }
 
-fn get_similar_paragraphs(sentence: &str) -> Result<SimilarityResultSet> {
-    let paragraphs = get_compare_set()?;
+/// Loads the references a batch saw and its centroid. Returns `None` if
+/// there is no such batch.
+fn load_batch(conn: &Connection, batch_id: &str) -> Result<Option<BatchContents>> {
+    let query_params = [sqlite::ValueParam::Text(batch_id)];
+    let result = conn.execute(
+        "SELECT centroid FROM batches WHERE batch_id = ?",
+        &query_params,
+    )?;
+    let centroid = match result.rows().next() {
+        Some(row) => match row.get::<&ValueResult>("centroid") {
+            Some(ValueResult::Blob(b)) => Some(serde_json::from_slice(b.as_slice())?),
+            _ => None,
+        },
+        None => return Ok(None),
+    };
+    let members = conn
+        .execute(
+            "SELECT reference FROM batch_paragraphs WHERE batch_id = ?",
+            &query_params,
+        )?
+        .rows()
+        .filter_map(|row| row.get::<&str>("reference").map(str::to_owned))
+        .collect();
+    Ok(Some(BatchContents { members, centroid }))
+}
 
-    let embedded_sentence: Vec<f32> = match generate_embeddings(AllMiniLmL6V2, &[sentence]) {
-        Ok(er) => {
-            trace!("Generated embeddings: {:?}", er);
-            er.embeddings
-                .get(0)
-                .expect("Embeddings results should always be populated")
-                .to_vec()
-        }
+fn get_batches(req: Request, _params: Params) -> Result<Response> {
+    let query: ListQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
         Err(err) => {
-            error!(
-                "Failed to generate embeddings when calling Spin llm: {:?}",
-                err
-            );
-            return Err(err.into());
+            error!("Failed to parse batches query: {:?}", err);
+            return bad_request("Invalid batches query");
         }
     };
 
-    let mut results: Vec<SimilarityResult> = paragraphs
-        .into_iter()
-        .map(|p| SimilarityResult {
-            similarity: cosine_similarity(p.embedding.as_ref(), embedded_sentence.as_ref()),
-            paragraph: Paragraph {
-                reference: p.reference,
-                text: p.text,
-            },
+    let query_params = [sqlite::ValueParam::Integer(query.limit.unwrap_or(10))];
+    let batches = Connection::open_default()?
+        .execute(
+            "SELECT b.batch_id, b.created_at, COUNT(p.reference) AS paragraphs FROM batches b
+            LEFT JOIN live_paragraphs p ON p.batch_id = b.batch_id
+            GROUP BY b.batch_id ORDER BY b.created_at DESC LIMIT ?",
+            &query_params,
+        )?
+        .rows()
+        .filter_map(|row| {
+            Some(Batch {
+                batch_id: row.get::<&str>("batch_id")?.to_owned(),
+                created_at: row.get::<&str>("created_at")?.to_owned(),
+                paragraphs: row.get::<i64>("paragraphs").unwrap_or_default(),
+            })
         })
-        .collect();
+        .collect::<Vec<Batch>>();
 
-    results.sort_by(|a, b| b.similarity.partial_cmp(&a.similarity).unwrap());
+    json_response(http::StatusCode::OK, &batches)
+}
 
-    let similarity_results = SimilarityResultSet {
-        sentence: sentence.to_string(),
-        results,
+/// Deletes the paragraphs stored by one ingest batch. Paragraphs that the batch
+/// replaced are deleted as well, as their previous version isn't kept.
+fn rollback_batch(req: Request, _params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+    let query: RollbackQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse rollback query: {:?}", err);
+            return bad_request("Invalid rollback query");
+        }
     };
 
-    Ok(similarity_results)
+    let conn = Connection::open_default()?;
+    let query_params = [sqlite::ValueParam::Text(&query.batch_id)];
+    conn.execute("DELETE FROM paragraphs WHERE batch_id = ?", &query_params)?;
+    let deleted = conn
+        .execute("SELECT changes() AS changed", &[])?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("changed"))
+        .unwrap_or_default();
+    conn.execute("DELETE FROM batches WHERE batch_id = ?", &query_params)?;
+    conn.execute(
+        "DELETE FROM batch_paragraphs WHERE batch_id = ?",
+        &query_params,
+    )?;
+    invalidate_search_index();
+    info!(
+        "Rolled back batch {} of {} paragraphs",
+        query.batch_id, deleted
+    );
+
+    json_response(http::StatusCode::OK, &DeleteResult { deleted })
 }
 
-fn get_compare_set() -> Result<Vec<ParagraphRecord>> {
-    let sql_query = "SELECT * FROM paragraphs";
-    match Connection::open_default()?
-        .execute(sql_query, &[])?
-        .rows()
-        .map(|row| -> anyhow::Result<ParagraphRecord> { row.try_into() })
-        .collect::<anyhow::Result<Vec<ParagraphRecord>>>()
-    {
-        Ok(er) => Ok(er),
+/// Fetches a page and ingests the text of its body, for clients that don't
+/// crawl themselves. The page is stored under its URL.
+fn ingest_url(req: Request, _params: Params) -> Result<Response> {
+    if !variable("allow_outbound_http", false)? {
+        return Ok(http::Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .body(Some(
+                "Fetching URLs is disabled, set allow_outbound_http".into(),
+            ))?);
+    }
+    let query: IngestUrlQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
         Err(err) => {
-            error!("Failed to get paragraphs to compare with");
-            Err(err)
+            error!("Failed to parse ingest-url query: {:?}", err);
+            return bad_request("Invalid ingest-url query");
         }
+    };
+    if !is_fetchable_url(&query.url) {
+        return bad_request("url must be a public http or https URL");
     }
-}
 
-fn cosine_similarity(vec1: &[f32], vec2: &[f32]) -> f32 {
-    let dot_product = vec1
-        .iter()
-        .zip(vec2.iter())
-        .map(|(x, y)| x * y)
-        .sum::<f32>();
-    let norm1 = vec1.iter().map(|x| x * x).sum::<f32>().sqrt();
-    let norm2 = vec2.iter().map(|y| y * y).sum::<f32>().sqrt();
-    dot_product / (norm1 * norm2)
+    let fetched = spin_sdk::outbound_http::send_request(
+        http::Request::builder()
+            .method("GET")
+            .uri(&query.url)
+            .body(None)?,
+    )?;
+    if !fetched.status().is_success() {
+        return Ok(http::Response::builder()
+            .status(http::StatusCode::BAD_GATEWAY)
+            .body(Some(
+                format!("Fetching {} returned {}", query.url, fetched.status()).into(),
+            ))?);
+    }
+    let html = String::from_utf8_lossy(fetched.body().as_deref().unwrap_or_default());
+    let text = strip_html(&html);
+    if text.is_empty() {
+        return bad_request("The page has no text content");
+    }
+
+    let page = fetched_page(&query.url, text);
+    let text_length = page.text.chars().count();
+    let result = ingest_pages(vec![page], &IngestParams::default())?;
+    if let Some(failure) = result.errors.first() {
+        anyhow::bail!("Failed to ingest {}: {}", failure.reference, failure.error);
+    }
+
+    json_response(
+        http::StatusCode::OK,
+        &IngestUrlResult {
+            reference: query.url,
+            text_length,
+            status: if result.stored > 0 {
+                "stored"
+            } else if result.skipped_duplicates > 0 {
+                "duplicate"
+            } else {
+                "unchanged"
+            },
+        },
+    )
 }
 
-impl<'a> TryFrom<sqlite::Row<'a>> for ParagraphRecord {
-    type Error = anyhow::Error;
+/// Ingests every page listed in a sitemap that isn't stored yet, all in one
+/// batch.
+fn ingest_sitemap(req: Request, _params: Params) -> Result<Response> {
+    if !variable("allow_outbound_http", false)? {
+        return Ok(http::Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .body(Some(
+                "Fetching URLs is disabled, set allow_outbound_http".into(),
+            ))?);
+    }
+    let query: IngestSitemapQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse ingest-sitemap query: {:?}", err);
+            return bad_request("Invalid ingest-sitemap query");
+        }
+    };
+    if !is_fetchable_url(&query.sitemap_url) {
+        return bad_request("sitemap_url must be a public http or https URL");
+    }
 
-    fn try_from(row: sqlite::Row<'a>) -> std::result::Result<Self, Self::Error> {
-        let embedding: Vec<f32> = match row.get::<&ValueResult>("embedding").unwrap() {
-            ValueResult::Blob(b) => {
-                serde_json::from_value(serde_json::from_slice(b.as_slice()).unwrap_or_default())?
+    let sitemap = match fetch_text(&query.sitemap_url) {
+        Ok(sitemap) => sitemap,
+        Err(err) => {
+            return Ok(http::Response::builder()
+                .status(http::StatusCode::BAD_GATEWAY)
+                .body(Some(format!("{:#}", err).into()))?)
+        }
+    };
+
+    let conn = Connection::open_default()?;
+    let mut pages = vec![];
+    let mut already_indexed = 0;
+    let mut errors = vec![];
+    for url in sitemap_locations(&sitemap) {
+        if !is_fetchable_url(&url) {
+            continue;
+        }
+        if paragraph_exists(&conn, &url)? {
+            already_indexed += 1;
+            continue;
+        }
+        // One broken link shouldn't keep the rest of the site out.
+        match fetch_text(&url) {
+            Ok(html) => {
+                let text = strip_html(&html);
+                if !text.is_empty() {
+                    pages.push(fetched_page(&url, text));
+                }
             }
-            _ => todo!(),
-        };
-        let reference = row
-            .get::<&str>("reference")
-            .context("reference column is empty")?;
-        let text = row.get::<&str>("text").context("text column is empty")?;
-        Ok(Self {
-            reference: reference.to_owned(),
-            text: text.to_owned(),
-            embedding,
-        })
+            Err(err) => {
+                error!("Failed to fetch {}: {:?}", url, err);
+                errors.push(IngestError {
+                    reference: url,
+                    error: format!("{:#}", err),
+                });
+            }
+        }
+    }
+
+    let mut queued = 0;
+    if !pages.is_empty() {
+        let result = ingest_pages(pages, &IngestParams::default())?;
+        queued = result.stored;
+        errors.extend(result.errors);
     }
+
+    json_response(
+        http::StatusCode::OK,
+        &IngestSitemapResult {
+            queued,
+            already_indexed,
+            failed: errors.len(),
+            errors,
+        },
+    )
 }
 
-impl<'a> TryFrom<sqlite::Row<'a>> for Paragraph {
-    type Error = anyhow::Error;
+fn is_http_url(url: &str) -> bool {
+    url.starts_with("http://") || url.starts_with("https://")
+}
 
-    fn try_from(row: sqlite::Row<'a>) -> std::result::Result<Self, Self::Error> {
-        let reference = row
-            .get::<&str>("reference")
-            .context("reference column is empty")?;
-        let text = row.get::<&str>("text").context("text column is empty")?;
-        Ok(Self {
-            reference: reference.to_owned(),
-            text: text.to_owned(),
-        })
+/// Whether a client may have the component fetch the URL: an http or https URL
+/// whose host isn't this machine or an address of a private or link-local
+/// network. `allowed_http_hosts` lets the component reach any host, so this is
+/// what keeps clients away from internal services. Host names are only
+/// checked as written, as Spin 1 can't resolve them beforehand.
+fn is_fetchable_url(url: &str) -> bool {
+    if !is_http_url(url) {
+        return false;
+    }
+    let Some((_, rest)) = url.split_once("://") else {
+        return false;
+    };
+    let authority = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = authority.rsplit('@').next().unwrap_or_default();
+    let host = match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    }
+    .trim_end_matches('.')
+    .to_lowercase();
+    if host.is_empty() || host == "localhost" || host.ends_with(".localhost") {
+        return false;
+    }
+    match host.parse::<std::net::IpAddr>() {
+        Ok(std::net::IpAddr::V4(ip)) => is_public_ipv4(ip),
+        Ok(std::net::IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+        // No top-level domain is numeric, so such a host is an IPv4 address
+        // in another notation, like `2130706433` or `0x7f.1`.
+        Err(_) => !host.rsplit('.').next().is_some_and(|label| {
+            label.starts_with("0x") || label.chars().all(|c| c.is_ascii_digit())
+        }),
     }
 }
 
-//AI model structure
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Paragraph {
-    reference: String,
-    text: String,
+fn is_public_ipv4(ip: std::net::Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    // 100.64.0.0/10 is the shared address space of carrier-grade NAT.
+    let shared = a == 100 && b & 0xc0 == 64;
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || shared)
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct ParagraphRecord {
+/// GETs the URL, failing unless it answers with a success status.
+fn fetch_text(url: &str) -> Result<String> {
+    let fetched = spin_sdk::outbound_http::send_request(
+        http::Request::builder().method("GET").uri(url).body(None)?,
+    )?;
+    if !fetched.status().is_success() {
+        anyhow::bail!("Fetching {} returned {}", url, fetched.status());
+    }
+    Ok(String::from_utf8_lossy(fetched.body().as_deref().unwrap_or_default()).into_owned())
+}
+
+/// Returns the contents of the `<loc>` elements of a sitemap, with the XML
+/// entities sitemaps are required to escape decoded. Not a full XML parser.
+fn sitemap_locations(sitemap: &str) -> Vec<String> {
+    let mut locations = vec![];
+    let mut rest = sitemap;
+    while let Some(start) = rest.find("<loc>") {
+        rest = &rest[start + "<loc>".len()..];
+        let end = match rest.find("</loc>") {
+            Some(end) => end,
+            None => break,
+        };
+        let location = rest[..end]
+            .trim()
+            .trim_start_matches("<![CDATA[")
+            .trim_end_matches("]]>")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&apos;", "'")
+            .replace("&amp;", "&");
+        if !location.is_empty() {
+            locations.push(location);
+        }
+        rest = &rest[end..];
+    }
+    locations
+}
+
+/// A page fetched from `url`, stored with the URL as its reference.
+fn fetched_page(url: &str, text: String) -> Page {
+    Page {
+        url: url.to_owned(),
+        crawl: Crawl {
+            loaded_url: url.to_owned(),
+            loaded_time: String::new(),
+            referrer_url: String::new(),
+            depth: 0,
+        },
+        metadata: Metadata {
+            canonical_url: url.to_owned(),
+            title: url.to_owned(),
+            description: String::new(),
+            author: None,
+            keywords: String::new(),
+            language_code: String::new(),
+        },
+        screenshot_url: None,
+        text,
+        tags: vec![],
+    }
+}
+
+/// Extracts the text of an HTML document's body, dropping tags, scripts and
+/// styles. Good enough for readable pages, not a full HTML parser.
+fn strip_html(html: &str) -> String {
+    let lowercase = html.to_ascii_lowercase();
+    let start = lowercase
+        .find("<body")
+        .and_then(|i| lowercase[i..].find('>').map(|j| i + j + 1))
+        .unwrap_or(0);
+    let end = lowercase[start..]
+        .find("</body")
+        .map_or(html.len(), |i| start + i);
+
+    let mut text = String::new();
+    let mut rest = start;
+    while rest < end {
+        let Some(tag) = lowercase[rest..end].find('<').map(|i| rest + i) else {
+            text.push_str(&html[rest..end]);
+            break;
+        };
+        text.push_str(&html[rest..tag]);
+        text.push(' ');
+        // Skip the content of scripts and styles along with their tags.
+        let closing = ["script", "style"]
+            .iter()
+            .find(|name| lowercase[tag + 1..].starts_with(*name))
+            .map(|name| format!("</{}", name));
+        let after_tag = match closing {
+            Some(closing) => lowercase[tag..end].find(&closing).map(|i| tag + i),
+            None => Some(tag),
+        }
+        .and_then(|i| lowercase[i..end].find('>').map(|j| i + j + 1));
+        rest = after_tag.unwrap_or(end);
+    }
+
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+fn warm_after_ingest() {
+    match warm_compare_set() {
+        Ok(count) => info!("Warmed {} paragraphs after ingest", count),
+        Err(err) => error!("Failed to warm paragraphs after ingest: {:?}", err),
+    }
+}
+
+fn summarize_text(_text: &str, max_sentences: usize) -> Result<SummarizationResult> {
+    summarize_text_with(Llama2Chat, _text, max_sentences)
+}
+
+fn summarize_text_with(
+    model: InferencingModel,
+    text: &str,
+    max_sentences: usize,
+) -> Result<SummarizationResult> {
+    let prompt = summarize_prompt();
+    let params = inference_params()?;
+    let inferencing_result = infer_with_options(
+        model,
+        &prompt
+            .replace("{MAX_SENTENCES}", &max_sentences.to_string())
+            .replace("{SENTENCE}", text),
+        params,
+    )?;
+    let (confidence, truncated) = summarization_confidence(
+        inferencing_result.usage.prompt_token_count,
+        inferencing_result.usage.generated_token_count,
+        params.max_tokens,
+    );
+    if truncated {
+        warn!("Summary hit the limit of {} tokens", params.max_tokens);
+    }
+    Ok(SummarizationResult {
+        summary: inferencing_result.text.trim().to_owned(),
+        confidence,
+        truncated,
+        prompt_hash: content_hash(&prompt),
+    })
+}
+
+/// Estimates from the token counts how trustworthy a summary is, from 0 to 1.
+/// A summary that used up `max_tokens` was cut off, and one far shorter than
+/// its input likely left most of it out. Returns the score and whether the
+/// summary was cut off.
+fn summarization_confidence(
+    prompt_tokens: u32,
+    generated_tokens: u32,
+    max_tokens: u32,
+) -> (f32, bool) {
+    // Input tokens per summary token above which content is likely lost.
+    const MAX_COMPRESSION_RATIO: f32 = 20.0;
+
+    let truncated = generated_tokens >= max_tokens;
+    let ratio = prompt_tokens as f32 / generated_tokens.max(1) as f32;
+    let mut confidence = (MAX_COMPRESSION_RATIO / ratio).min(1.0);
+    if truncated {
+        confidence *= 0.5;
+    }
+    (confidence, truncated)
+}
+
+/// The summarization prompt template, as stored in the `prompts` table or the
+/// built-in default.
+fn summarize_prompt() -> String {
+    const PROMPT: &str = r#"<s>[INST]<<SYS>>You are a professional summarization assistant. Take the input text and return a summary in {MAX_SENTENCES} sentences. Please keep your responses concise, up to {MAX_SENTENCES} sentences..<</SYS>>Please summarize following text: {SENTENCE} [/INST]"#;
+
+    prompt_template("summarize", PROMPT)
+}
+
+/// Looks up the named prompt in the `prompts` table, so that prompts can be
+/// tuned without a deployment. Falls back to `default` when it isn't stored.
+fn prompt_template(name: &str, default: &str) -> String {
+    let lookup = || -> Result<Option<String>> {
+        let query_params = [sqlite::ValueParam::Text(name)];
+        let template = Connection::open_default()?
+            .execute("SELECT template FROM prompts WHERE name = ?", &query_params)?
+            .rows()
+            .next()
+            .and_then(|row| row.get::<&str>("template").map(str::to_owned));
+        Ok(template)
+    };
+    match lookup() {
+        Ok(Some(template)) => template,
+        Ok(None) => default.to_owned(),
+        Err(err) => {
+            error!("Failed to look up prompt {}: {:?}", name, err);
+            default.to_owned()
+        }
+    }
+}
+
+/// Asks the LLM for a passage answering the question, for Hypothetical
+/// Document Embeddings (HyDE): an answer, even a partly wrong one, tends to
+/// be closer to the paragraphs holding the real answer than the question is.
+fn hypothetical_document(question: &str) -> Result<String> {
+    const PROMPT: &str = r#"<s>[INST]<<SYS>>You write passages for a search engine. Reply with a single paragraph that answers the question as a reference document would, without any introduction.<</SYS>>Question: {QUESTION} [/INST]"#;
+
+    let prompt = prompt_template("hyde", PROMPT).replace("{QUESTION}", question);
+    let inferencing_result = infer_with_options(Llama2Chat, &prompt, inference_params()?)?;
+    let document = inferencing_result.text.trim();
+    if document.is_empty() {
+        anyhow::bail!("LLM returned no hypothetical document");
+    }
+    Ok(document.to_owned())
+}
+
+/// Asks the LLM for up to `count` rephrasings of the question, so that
+/// paragraphs using other words for the same thing are found as well.
+fn expand_query(question: &str, count: usize) -> Result<Vec<String>> {
+    const PROMPT: &str = r#"<s>[INST]<<SYS>>You help a search engine find documents. Reply with {COUNT} different rephrasings of the question, using other words where possible, one per line and without numbering.<</SYS>>Question: {QUESTION} [/INST]"#;
+
+    let prompt = prompt_template("query_expansion", PROMPT)
+        .replace("{COUNT}", &count.to_string())
+        .replace("{QUESTION}", question);
+    let inferencing_result = infer_with_options(Llama2Chat, &prompt, inference_params()?)?;
+    let queries: Vec<String> = inferencing_result
+        .text
+        .lines()
+        // Models tend to number their lines anyway.
+        .map(|line| line.trim_start_matches(|c: char| c.is_ascii_digit() || ".-*)".contains(c)))
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.eq_ignore_ascii_case(question))
+        .take(count)
+        .map(str::to_owned)
+        .collect();
+    if queries.is_empty() {
+        anyhow::bail!("LLM returned no rephrasings");
+    }
+    Ok(queries)
+}
+
+fn translate_sentence(sentence: &str, target_language: &str) -> Result<String> {
+    const PROMPT: &str = r#"<s>[INST]<<SYS>>You are a professional translator. Reply with the translation only. If the text is already in the target language, repeat it unchanged.<</SYS>>Translate the following text to the language with ISO 639-1 code "{LANGUAGE}": {SENTENCE} [/INST]"#;
+
+    let prompt = PROMPT
+        .replace("{LANGUAGE}", target_language)
+        .replace("{SENTENCE}", sentence);
+    let inferencing_result = infer(Llama2Chat, &prompt)?;
+    let translated = inferencing_result.text.trim();
+    if translated.is_empty() {
+        anyhow::bail!("LLM returned no translation");
+    }
+    Ok(translated.to_owned())
+}
+
+/// Runs an inference like `spin_sdk::llm::infer`, which can't pass the
+/// request's correlation id on to the host, so it is logged right before.
+fn infer(
+    model: InferencingModel,
+    prompt: &str,
+) -> std::result::Result<InferencingResult, spin_sdk::llm::Error> {
+    log_correlation_id();
+    spin_sdk::llm::infer(model, prompt)
+}
+
+/// Like `infer`, runs `spin_sdk::llm::infer_with_options`.
+fn infer_with_options(
+    model: InferencingModel,
+    prompt: &str,
+    params: InferencingParams,
+) -> std::result::Result<InferencingResult, spin_sdk::llm::Error> {
+    log_correlation_id();
+    spin_sdk::llm::infer_with_options(model, prompt, params)
+}
+
+fn log_correlation_id() {
+    match CORRELATION_ID.lock() {
+        Ok(id) => {
+            if let Some(id) = id.as_deref() {
+                info!("Calling the LLM for request {}", id);
+            }
+        }
+        Err(_) => error!("Correlation id lock is poisoned"),
+    }
+}
+
+/// Summarization parameters, read from the `infer_max_tokens` and
+/// `infer_temperature` Spin variables.
+fn inference_params() -> Result<InferencingParams> {
+    let max_tokens: u32 = variable("infer_max_tokens", 150)?;
+    let temperature: f32 = variable("infer_temperature", 0.7)?;
+
+    if !(10..=2048).contains(&max_tokens) {
+        anyhow::bail!(
+            "infer_max_tokens must be between 10 and 2048, got {}",
+            max_tokens
+        );
+    }
+    if !(temperature > 0.0 && temperature <= 2.0) {
+        anyhow::bail!(
+            "infer_temperature must be in (0.0, 2.0], got {}",
+            temperature
+        );
+    }
+
+    Ok(InferencingParams {
+        max_tokens,
+        temperature,
+        ..Default::default()
+    })
+}
+
+/// Reads a Spin variable, falling back to `default` when it isn't set.
+fn variable<T>(name: &str, default: T) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match spin_sdk::config::get(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|err| anyhow::anyhow!("Invalid value for variable {}: {}", name, err)),
+        Err(_) => Ok(default),
+    }
+}
+
+fn store_paragraph_record(
+    conn: &Connection,
+    corpus: &DocumentFrequencies,
+    page: &Page,
+    embedding: Vec<f32>,
+    summary: Option<SummarizationResult>,
+    batch_id: &str,
+) -> Result<()> {
+    let language_code = if page.metadata.language_code.is_empty() {
+        detect_language(&page.text, AllMiniLmL6V2).unwrap_or_else(|err| {
+            error!(
+                "Failed to detect the language of {}: {:?}",
+                page.metadata.title, err
+            );
+            "unknown".to_owned()
+        })
+    } else {
+        page.metadata.language_code.clone()
+    };
+    let record = NewParagraph {
+        reference: &page.metadata.title,
+        text: &page.text,
+        embedding,
+        crawl_depth: Some(page.crawl.depth),
+        url: Some(&page.url),
+        referrer_url: Some(&page.crawl.referrer_url),
+        source_domain: domain_of(&page.url),
+        tags: &page.tags,
+        keywords_extracted: extract_keywords(&page.text, KEYWORDS_PER_PARAGRAPH, corpus),
+        loaded_at: Some(page.crawl.loaded_time.as_str()).filter(|t| !t.is_empty()),
+        batch_id: Some(batch_id),
+        summary: summary.as_ref(),
+        language_code: Some(&language_code),
+        source_type: SourceType::of_url(&page.url),
+    };
+    insert_paragraph(conn, &record)
+}
+
+fn insert_paragraph(conn: &Connection, record: &NewParagraph) -> Result<()> {
+    let stored = StoredEmbedding::encode(&record.embedding)?;
+    let tags = serde_json::to_string(record.tags)?;
+    let keywords = serde_json::to_string(&record.keywords_extracted)?;
+    let hash = content_hash(record.text);
+    let simhash = simhash(record.text) as i64;
+    let sentence_count = sentence_count(record.text);
+
+    let query_params = [
+        sqlite::ValueParam::Text(record.reference),
+        sqlite::ValueParam::Text(record.text),
+        sqlite::ValueParam::Blob(stored.blob.as_slice()),
+        sqlite::ValueParam::Text(EMBEDDING_MODEL),
+        record.crawl_depth.map_or(sqlite::ValueParam::Null, |d| {
+            sqlite::ValueParam::Integer(d.into())
+        }),
+        sqlite::ValueParam::Integer(word_count(record.text)),
+        record
+            .source_domain
+            .as_deref()
+            .map_or(sqlite::ValueParam::Null, sqlite::ValueParam::Text),
+        sqlite::ValueParam::Text(tags.as_str()),
+        sqlite::ValueParam::Text(hash.as_str()),
+        sqlite::ValueParam::Text(keywords.as_str()),
+        record
+            .url
+            .map_or(sqlite::ValueParam::Null, sqlite::ValueParam::Text),
+        record
+            .referrer_url
+            .map_or(sqlite::ValueParam::Null, sqlite::ValueParam::Text),
+        record
+            .loaded_at
+            .map_or(sqlite::ValueParam::Null, sqlite::ValueParam::Text),
+        record
+            .batch_id
+            .map_or(sqlite::ValueParam::Null, sqlite::ValueParam::Text),
+        sqlite::ValueParam::Integer(stored.compressed.into()),
+        sqlite::ValueParam::Text(stored.format.as_str()),
+        record.summary.map_or(sqlite::ValueParam::Null, |s| {
+            sqlite::ValueParam::Text(s.summary.as_str())
+        }),
+        record.summary.map_or(sqlite::ValueParam::Null, |s| {
+            sqlite::ValueParam::Text(s.prompt_hash.as_str())
+        }),
+        record.summary.map_or(sqlite::ValueParam::Null, |s| {
+            sqlite::ValueParam::Real(s.confidence.into())
+        }),
+        sqlite::ValueParam::Integer(simhash),
+        sqlite::ValueParam::Integer(sentence_count),
+        record
+            .language_code
+            .map_or(sqlite::ValueParam::Null, sqlite::ValueParam::Text),
+        sqlite::ValueParam::Text(record.source_type.as_str()),
+    ];
+
+    // Changed text for an existing reference replaces the stored row but
+    // keeps its retrieval statistics and page_id. The replaced version stays in
+    // its timeline.
+    archive_replaced_paragraph(conn, record.reference)?;
+    conn.execute(
+        "INSERT INTO paragraphs ('reference', 'text', 'embedding', 'model', 'crawl_depth', 'word_count', 'source_domain', 'tags', 'content_hash', 'keywords_extracted', 'url', 'referrer_url', 'loaded_at', 'batch_id', 'compressed', 'format', 'summary', 'prompt_hash', 'summarization_confidence', 'simhash', 'sentence_count', 'language_code', 'source_type', 'page_id') VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, COALESCE(?, datetime('now')), ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, lower(hex(randomblob(16))))
+        ON CONFLICT(reference) DO UPDATE SET text = excluded.text, embedding = excluded.embedding, model = excluded.model, crawl_depth = excluded.crawl_depth, word_count = excluded.word_count, source_domain = excluded.source_domain, tags = excluded.tags, content_hash = excluded.content_hash, keywords_extracted = excluded.keywords_extracted, url = excluded.url, referrer_url = excluded.referrer_url, loaded_at = excluded.loaded_at, batch_id = excluded.batch_id, compressed = excluded.compressed, format = excluded.format, summary = excluded.summary, prompt_hash = excluded.prompt_hash, summarization_confidence = excluded.summarization_confidence, simhash = excluded.simhash, sentence_count = excluded.sentence_count, language_code = excluded.language_code, source_type = excluded.source_type, deleted_at = NULL, sentence_embeddings = NULL, version = IFNULL(paragraphs.version, 1) + 1;",
+        &query_params,
+    )?;
+    Ok(())
+}
+
+/// Guesses the language of a text by the reference sentence its beginning is
+/// most similar to, returning its ISO 639-1 code. Only a rough guess, which
+/// is the best a model trained mostly on English can do.
+fn detect_language(text: &str, model: EmbeddingModel) -> Result<String> {
+    const SAMPLE_WORDS: usize = 50;
+    const REFERENCES: [(&str, &str); 8] = [
+        (
+            "en",
+            "This is a short text written in English about everyday things.",
+        ),
+        (
+            "es",
+            "Este es un texto corto escrito en español sobre cosas cotidianas.",
+        ),
+        (
+            "fr",
+            "Ceci est un court texte écrit en français sur les choses du quotidien.",
+        ),
+        (
+            "de",
+            "Dies ist ein kurzer Text auf Deutsch über alltägliche Dinge.",
+        ),
+        (
+            "it",
+            "Questo è un breve testo scritto in italiano sulle cose di tutti i giorni.",
+        ),
+        (
+            "pt",
+            "Este é um texto curto escrito em português sobre coisas do dia a dia.",
+        ),
+        (
+            "nl",
+            "Dit is een korte tekst in het Nederlands over alledaagse dingen.",
+        ),
+        (
+            "cs",
+            "Toto je krátký text napsaný česky o každodenních věcech.",
+        ),
+    ];
+
+    let sample = text
+        .split_whitespace()
+        .take(SAMPLE_WORDS)
+        .collect::<Vec<_>>()
+        .join(" ");
+    if sample.is_empty() {
+        anyhow::bail!("The text is empty");
+    }
+    let texts: Vec<&str> = std::iter::once(sample.as_str())
+        .chain(REFERENCES.iter().map(|(_, sentence)| *sentence))
+        .collect();
+    let embeddings = generate_embeddings(model, &texts)?.embeddings;
+    log_embedding_stats(&texts, &embeddings);
+    let (sample_embedding, reference_embeddings) = embeddings
+        .split_first()
+        .context("LLM returned no embeddings")?;
+
+    REFERENCES
+        .iter()
+        .zip(reference_embeddings)
+        .map(|((code, _), embedding)| (code, cosine_similarity(sample_embedding, embedding)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(code, _)| (*code).to_owned())
+        .context("LLM returned no reference embeddings")
+}
+
+fn content_hash(text: &str) -> String {
+    blake3::hash(text.as_bytes()).to_hex().to_string()
+}
+
+/// SimHash of the words of a text: texts that share most of their words get
+/// hashes that differ in only a few bits.
+fn simhash(text: &str) -> u64 {
+    let mut weights = [0i64; 64];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        let digest = blake3::hash(word.to_lowercase().as_bytes());
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest.as_bytes()[..8]);
+        let hash = u64::from_le_bytes(bytes);
+        for (bit, weight) in weights.iter_mut().enumerate() {
+            if hash >> bit & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    weights
+        .iter()
+        .enumerate()
+        .filter(|(_, weight)| **weight > 0)
+        .fold(0, |hash, (bit, _)| hash | 1 << bit)
+}
+
+/// Loads the SimHash of every stored paragraph. At 8 bytes per paragraph
+/// this is cheap, and SQLite can't count differing bits by itself.
+fn load_simhashes(conn: &Connection) -> Result<Vec<(String, u64)>> {
+    let hashes = conn
+        .execute(
+            "SELECT reference, simhash FROM live_paragraphs WHERE simhash IS NOT NULL",
+            &[],
+        )?
+        .rows()
+        .filter_map(|row| {
+            Some((
+                row.get::<&str>("reference")?.to_owned(),
+                row.get::<i64>("simhash")? as u64,
+            ))
+        })
+        .collect();
+    Ok(hashes)
+}
+
+fn is_unchanged(conn: &Connection, reference: &str, text: &str) -> Result<bool> {
+    let query_params = [sqlite::ValueParam::Text(reference)];
+    let result = conn.execute(
+        "SELECT content_hash FROM live_paragraphs WHERE reference = ?",
+        &query_params,
+    )?;
+    let unchanged = result
+        .rows()
+        .next()
+        .and_then(|row| row.get::<&str>("content_hash"))
+        .is_some_and(|hash| hash == content_hash(text));
+    Ok(unchanged)
+}
+
+fn segment_text(req: Request, _params: Params) -> Result<Response> {
+    let request: SegmentRequest = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(r) => r,
+        Err(err) => {
+            error!("Failed to parse segment request: {:?}", err);
+            return bad_request("Invalid segment request");
+        }
+    };
+
+    let segments = chunk_text(&request.text, &request.strategy);
+    if segments.is_empty() {
+        return bad_request("Text contains no segments");
+    }
+
+    let segment_refs: Vec<&str> = segments.iter().map(AsRef::as_ref).collect();
+    let embeddings = generate_normalized_embeddings(&segment_refs)?.embeddings;
+
+    let conn = Connection::open_default()?;
+    let corpus = DocumentFrequencies::load(&conn)?;
+    let mut segments_stored = 0;
+    for (i, (segment, embedding)) in segments.iter().zip(embeddings).enumerate() {
+        let reference = format!("{}#{}", request.reference_prefix, i);
+        let record = NewParagraph {
+            reference: &reference,
+            text: segment,
+            embedding,
+            crawl_depth: None,
+            url: None,
+            referrer_url: None,
+            source_domain: domain_of(&request.reference_prefix),
+            tags: &[],
+            keywords_extracted: extract_keywords(segment, KEYWORDS_PER_PARAGRAPH, &corpus),
+            loaded_at: None,
+            batch_id: None,
+            summary: None,
+            language_code: None,
+            source_type: SourceType::Manual,
+        };
+        match insert_paragraph(&conn, &record) {
+            Ok(()) => segments_stored += 1,
+            Err(err) => error!("Failed to insert {}: {:?}", reference, err),
+        }
+    }
+    invalidate_search_index();
+    info!(
+        "Stored {} segments of {}",
+        segments_stored, request.reference_prefix
+    );
+
+    json_response(
+        http::StatusCode::CREATED,
+        &SegmentResult { segments_stored },
+    )
+}
+
+/// Returns the crawl graph of a domain for visualization. Like the PageRank
+/// graph, it only links pages that are stored.
+fn get_anchor_graph(req: Request, _params: Params) -> Result<Response> {
+    // Larger graphs tend to freeze browsers rendering them.
+    const MAX_NODES: usize = 500;
+
+    let query: AnchorGraphQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse anchor graph query: {:?}", err);
+            return bad_request("Missing domain query parameter");
+        }
+    };
+
+    let query_params = [sqlite::ValueParam::Text(&query.domain)];
+    let rows = Connection::open_default()?.execute(
+        "SELECT reference, url, referrer_url, crawl_depth FROM live_paragraphs WHERE source_domain = ?",
+        &query_params,
+    )?;
+
+    let mut nodes: Vec<AnchorNode> = vec![];
+    let mut referrers: Vec<Option<&str>> = vec![];
+    let mut seen: HashSet<&str> = HashSet::new();
+    for row in rows.rows() {
+        let Some(id) = row.get::<&str>("url").or(row.get::<&str>("reference")) else {
+            continue;
+        };
+        if nodes.len() == MAX_NODES {
+            break;
+        }
+        if seen.insert(id) {
+            nodes.push(AnchorNode {
+                id: id.to_owned(),
+                depth: row.get::<i64>("crawl_depth"),
+            });
+            referrers.push(row.get::<&str>("referrer_url"));
+        }
+    }
+
+    let edges = nodes
+        .iter()
+        .zip(referrers)
+        .filter_map(|(node, referrer)| {
+            let referrer = referrer.filter(|r| *r != node.id && seen.contains(r))?;
+            Some(AnchorEdge {
+                source: referrer.to_owned(),
+                target: node.id.clone(),
+            })
+        })
+        .collect();
+
+    json_response(http::StatusCode::OK, &AnchorGraph { nodes, edges })
+}
+
+/// Searches the pages the crawler reached from `start` within `max_hops`
+/// steps, returning the best results at each number of steps.
+fn search_from_anchor(req: Request, _params: Params) -> Result<Response> {
+    let query: AnchorSearchQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse anchor search: {:?}", err);
+            return bad_request("Invalid anchor search");
+        }
+    };
+    if !(1..=10).contains(&query.max_hops) {
+        return bad_request("max_hops must be between 1 and 10");
+    }
+
+    let conn = Connection::open_default()?;
+    let rows = conn.execute(
+        "SELECT reference, url, referrer_url FROM live_paragraphs",
+        &[],
+    )?;
+    // Like in `POST /anchor-graph`, pages are identified by their URL and
+    // link to the pages the crawler found through them.
+    let mut paragraphs_of: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut links: HashMap<&str, Vec<&str>> = HashMap::new();
+    for row in rows.rows() {
+        let Some(reference) = row.get::<&str>("reference") else {
+            continue;
+        };
+        let page = row.get::<&str>("url").unwrap_or(reference);
+        paragraphs_of.entry(page).or_default().push(reference);
+        if let Some(referrer) = row.get::<&str>("referrer_url").filter(|r| *r != page) {
+            links.entry(referrer).or_default().push(page);
+        }
+    }
+    if !paragraphs_of.contains_key(query.start.as_str()) {
+        return not_found();
+    }
+
+    let embedded_sentence = embed_sentence(&query.sentence)?;
+    let mut visited: HashSet<&str> = HashSet::from([query.start.as_str()]);
+    let mut frontier = vec![query.start.as_str()];
+    let mut results_by_depth = vec![];
+    for _ in 0..query.max_hops {
+        frontier = frontier
+            .iter()
+            .filter_map(|page| links.get(page))
+            .flatten()
+            .copied()
+            .filter(|page| visited.insert(page))
+            .collect();
+
+        let mut results = vec![];
+        for reference in frontier
+            .iter()
+            .filter_map(|page| paragraphs_of.get(page))
+            .flatten()
+        {
+            if let Some(record) = find_paragraph_record(&conn, reference)? {
+                let similarity = cosine_similarity(&record.embedding, &embedded_sentence);
+                results.push(SimilarityResult::new(record.into_paragraph(), similarity));
+            }
+        }
+        results.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        results.truncate(query.top_k);
+        results_by_depth.push(results);
+    }
+
+    json_response(
+        http::StatusCode::OK,
+        &AnchorSearchResult {
+            start: query.start,
+            max_hops: query.max_hops,
+            results_by_depth,
+        },
+    )
+}
+
+fn compute_page_rank(_req: Request, _params: Params) -> Result<Response> {
+    const ITERATIONS: usize = 20;
+
+    let conn = Connection::open_default()?;
+    let rows = conn.execute(
+        "SELECT reference, url, referrer_url FROM live_paragraphs",
+        &[],
+    )?;
+    let pages: Vec<(&str, Option<&str>, Option<&str>)> = rows
+        .rows()
+        .filter_map(|row| {
+            Some((
+                row.get::<&str>("reference")?,
+                row.get::<&str>("url"),
+                row.get::<&str>("referrer_url"),
+            ))
+        })
+        .collect();
+
+    // A page links to every page it referred the crawler to. Referrers that
+    // aren't stored themselves don't take part in the graph.
+    let nodes: HashMap<&str, usize> = pages
+        .iter()
+        .enumerate()
+        .map(|(i, (reference, url, _))| (url.unwrap_or(reference), i))
+        .collect();
+    let mut links = vec![Vec::new(); pages.len()];
+    for (i, (_, _, referrer_url)) in pages.iter().enumerate() {
+        if let Some(&referrer) = referrer_url.and_then(|url| nodes.get(url)) {
+            if referrer != i {
+                links[referrer].push(i);
+            }
+        }
+    }
+
+    let ranks = page_rank(&links, ITERATIONS);
+    for ((reference, _, _), rank) in pages.iter().zip(&ranks) {
+        let query_params = [
+            sqlite::ValueParam::Real(*rank),
+            sqlite::ValueParam::Text(reference),
+        ];
+        conn.execute(
+            "UPDATE paragraphs SET page_rank = ? WHERE reference = ?",
+            &query_params,
+        )?;
+    }
+    info!("Computed PageRank of {} paragraphs", ranks.len());
+
+    json_response(
+        http::StatusCode::OK,
+        &PageRankResult {
+            paragraphs_ranked: ranks.len(),
+        },
+    )
+}
+
+/// Runs `iterations` power iterations of PageRank over `links`, where
+/// `links[i]` lists the nodes that node `i` links to. Ranks sum to 1.
+fn page_rank(links: &[Vec<usize>], iterations: usize) -> Vec<f64> {
+    const DAMPING: f64 = 0.85;
+
+    let n = links.len();
+    if n == 0 {
+        return vec![];
+    }
+    let mut ranks = vec![1.0 / n as f64; n];
+    for _ in 0..iterations {
+        // Nodes without outgoing links spread their rank over every node.
+        let dangling: f64 = (0..n)
+            .filter(|&i| links[i].is_empty())
+            .map(|i| ranks[i])
+            .sum();
+        let mut next = vec![(1.0 - DAMPING + DAMPING * dangling) / n as f64; n];
+        for (i, targets) in links.iter().enumerate() {
+            for &target in targets {
+                next[target] += DAMPING * ranks[i] / targets.len() as f64;
+            }
+        }
+        ranks = next;
+    }
+    ranks
+}
+
+fn warm_paragraphs(_req: Request, _params: Params) -> Result<Response> {
+    let paragraphs_loaded = warm_compare_set()?;
+    info!("Warmed {} paragraphs", paragraphs_loaded);
+
+    json_response(http::StatusCode::OK, &WarmResult { paragraphs_loaded })
+}
+
+fn get_prompts(_req: Request, _params: Params) -> Result<Response> {
+    let prompts = Connection::open_default()?
+        .execute("SELECT name, template FROM prompts ORDER BY name", &[])?
+        .rows()
+        .filter_map(|row| {
+            Some(Prompt {
+                name: row.get::<&str>("name")?.to_owned(),
+                template: row.get::<&str>("template")?.to_owned(),
+            })
+        })
+        .collect::<Vec<Prompt>>();
+
+    json_response(http::StatusCode::OK, &prompts)
+}
+
+/// Stores a named prompt, replacing any prompt of the same name.
+fn save_prompt(req: Request, _params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+    let prompt: Prompt = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(p) => p,
+        Err(err) => {
+            error!("Failed to parse prompt: {:?}", err);
+            return bad_request("Invalid prompt");
+        }
+    };
+    if prompt.name.is_empty() || prompt.template.trim().is_empty() {
+        return bad_request("name and template must not be empty");
+    }
+
+    let query_params = [
+        sqlite::ValueParam::Text(&prompt.name),
+        sqlite::ValueParam::Text(&prompt.template),
+    ];
+    Connection::open_default()?.execute(
+        "INSERT INTO prompts (name, template) VALUES (?, ?)
+        ON CONFLICT(name) DO UPDATE SET template = excluded.template",
+        &query_params,
+    )?;
+    info!("Saved prompt {}", prompt.name);
+
+    json_response(http::StatusCode::OK, &prompt)
+}
+
+/// Compresses the embeddings stored before the `zstd` feature was enabled.
+#[cfg(feature = "zstd")]
+fn compress_embeddings(req: Request, _params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+
+    let conn = Connection::open_default()?;
+    let rows = conn.execute(
+        "SELECT reference, embedding FROM paragraphs WHERE IFNULL(compressed, 0) = 0",
+        &[],
+    )?;
+    let mut compressed = 0;
+    for row in rows.rows() {
+        let (Some(reference), Some(ValueResult::Blob(blob))) = (
+            row.get::<&str>("reference"),
+            row.get::<&ValueResult>("embedding"),
+        ) else {
+            continue;
+        };
+        let (blob, _) = compress_blob(blob.clone())?;
+        let query_params = [
+            sqlite::ValueParam::Blob(blob.as_slice()),
+            sqlite::ValueParam::Text(reference),
+        ];
+        conn.execute(
+            "UPDATE paragraphs SET embedding = ?, compressed = 1 WHERE reference = ?",
+            &query_params,
+        )?;
+        compressed += 1;
+    }
+    info!("Compressed {} embeddings", compressed);
+
+    json_response(http::StatusCode::OK, &CompressResult { compressed })
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_embeddings(_req: Request, _params: Params) -> Result<Response> {
+    Ok(http::Response::builder()
+        .status(http::StatusCode::NOT_IMPLEMENTED)
+        .body(Some("Built without the zstd feature".into()))?)
+}
+
+/// Summarizes up to `batch_size` paragraphs stored without a summary, e.g.
+/// before the `summary` column existed. Meant to be called repeatedly until
+/// `remaining` reaches 0. Only the summary is stored, the embedding is kept.
+fn backfill_summaries(req: Request, _params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+    let query: BackfillQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse backfill query: {:?}", err);
+            return bad_request("Invalid backfill query");
+        }
+    };
+    if !(1..=100).contains(&query.batch_size) {
+        return bad_request("batch_size must be between 1 and 100");
+    }
+
+    let conn = Connection::open_default()?;
+    let query_params = [sqlite::ValueParam::Integer(query.batch_size)];
+    let rows = conn.execute(
+        "SELECT reference, text FROM live_paragraphs WHERE summary IS NULL LIMIT ?",
+        &query_params,
+    )?;
+    let mut processed = 0;
+    for row in rows.rows() {
+        let (Some(reference), Some(text)) = (row.get::<&str>("reference"), row.get::<&str>("text"))
+        else {
+            continue;
+        };
+        // A failure leaves the row for the next call rather than failing the batch.
+        let summary = match summarize_text(text, SUMMARY_SENTENCES) {
+            Ok(summary) => summary,
+            Err(err) => {
+                error!("Failed to summarize {}: {:?}", reference, err);
+                continue;
+            }
+        };
+        let query_params = [
+            sqlite::ValueParam::Text(&summary.summary),
+            sqlite::ValueParam::Text(&summary.prompt_hash),
+            sqlite::ValueParam::Real(summary.confidence.into()),
+            sqlite::ValueParam::Text(reference),
+        ];
+        conn.execute(
+            "UPDATE paragraphs SET summary = ?, prompt_hash = ?, summarization_confidence = ? WHERE reference = ?",
+            &query_params,
+        )?;
+        processed += 1;
+    }
+    let remaining = conn
+        .execute(
+            "SELECT COUNT(*) AS remaining FROM live_paragraphs WHERE summary IS NULL",
+            &[],
+        )?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("remaining"))
+        .unwrap_or_default();
+    info!(
+        "Backfilled {} summaries, {} remaining",
+        processed, remaining
+    );
+
+    json_response(
+        http::StatusCode::OK,
+        &BackfillResult {
+            processed,
+            remaining,
+        },
+    )
+}
+
+/// Counts the summaries generated with a prompt other than `prompt_hash`, the
+/// current summarization prompt by default, so that they can be regenerated.
+fn get_backfill_needed(req: Request, _params: Params) -> Result<Response> {
+    let query: BackfillNeededQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default())
+    {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse backfill-needed query: {:?}", err);
+            return bad_request("Invalid backfill-needed query");
+        }
+    };
+    let prompt_hash = query
+        .prompt_hash
+        .unwrap_or_else(|| content_hash(&summarize_prompt()));
+
+    let query_params = [sqlite::ValueParam::Text(&prompt_hash)];
+    let count = Connection::open_default()?
+        .execute(
+            "SELECT COUNT(*) AS count FROM live_paragraphs WHERE summary IS NOT NULL AND IFNULL(prompt_hash, '') != ?",
+            &query_params,
+        )?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("count"))
+        .unwrap_or_default();
+
+    json_response(http::StatusCode::OK, &BackfillNeeded { prompt_hash, count })
+}
+
+fn vacuum_database(req: Request, _params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+
+    let conn = Connection::open_default()?;
+    info!(
+        "Vacuuming database with {} paragraphs",
+        count_paragraphs(&conn)?
+    );
+    conn.execute("PRAGMA wal_checkpoint(TRUNCATE)", &[])?;
+    conn.execute("VACUUM", &[])?;
+    info!(
+        "Vacuumed database, {} paragraphs remain",
+        count_paragraphs(&conn)?
+    );
+
+    json_response(
+        http::StatusCode::OK,
+        &AdminResult {
+            status: "ok",
+            message: "vacuum complete",
+        },
+    )
+}
+
+/// Shows how SQLite runs the list queries, to check they use the indexes
+/// created by `ensure_schema` instead of scanning the table.
+fn get_query_plans(req: Request, _params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+
+    let conn = Connection::open_default()?;
+    let mut plans = Vec::with_capacity(PLANNED_QUERIES.len());
+    for (index, query) in PLANNED_QUERIES {
+        let details: Vec<String> = conn
+            .execute(&format!("EXPLAIN QUERY PLAN {query}"), &[])?
+            .rows()
+            .filter_map(|row| row.get::<&str>("detail").map(str::to_string))
+            .collect();
+        let uses_index = details.iter().any(|detail| detail.contains(index));
+        if !uses_index {
+            warn!("Query does not use {}: {}", index, query);
+        }
+        plans.push(QueryPlan {
+            query,
+            index,
+            uses_index,
+            details,
+        });
+    }
+
+    json_response(http::StatusCode::OK, &plans)
+}
+
+fn clear_query_cache(req: Request, _params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+    let query: CacheQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse query cache query: {:?}", err);
+            return bad_request("Invalid query cache query");
+        }
+    };
+
+    let conn = Connection::open_default()?;
+    match &query.older_than {
+        Some(older_than) => {
+            let days = match parse_days(older_than) {
+                Some(days) => days,
+                None => return bad_request("older_than must be a number of days, e.g. 7d"),
+            };
+            let modifier = format!("-{} days", days);
+            let query_params = [sqlite::ValueParam::Text(&modifier)];
+            conn.execute(
+                "DELETE FROM sentence_embeddings_cache WHERE created_at < datetime('now', ?)",
+                &query_params,
+            )?;
+        }
+        None => {
+            conn.execute("DELETE FROM sentence_embeddings_cache", &[])?;
+        }
+    }
+    let deleted = conn
+        .execute("SELECT changes() AS changed", &[])?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("changed"))
+        .unwrap_or_default();
+    info!("Deleted {} cached query embeddings", deleted);
+
+    json_response(http::StatusCode::OK, &DeleteResult { deleted })
+}
+
+/// Parses a duration in days such as `7d`.
+fn parse_days(duration: &str) -> Option<u32> {
+    duration.strip_suffix('d')?.parse().ok()
+}
+
+fn delete_paragraph_record(_req: Request, params: Params) -> Result<Response> {
+    let status = match params.get("reference") {
+        Some(reference) => {
+            let query_params = [sqlite::ValueParam::Text(reference)];
+            let conn = Connection::open_default()?;
+            let _ = conn.execute(
+                "DELETE FROM paragraphs WHERE reference = (?)",
+                &query_params,
+            );
+            invalidate_search_index();
+            info!("Deleted one record");
+            http::StatusCode::OK
+        }
+        None => http::StatusCode::NOT_FOUND,
+    };
+
+    Ok(http::Response::builder().status(status).body(None)?)
+}
+
+/// Deletes every paragraph from a domain, e.g. after access to the site was
+/// revoked. Admin only, and as this can't be undone it also needs an
+/// `X-Confirm-Wipe: yes` header.
+fn delete_domain(req: Request, params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+    let Some(domain) = params.get("domain") else {
+        return not_found();
+    };
+    let confirmed = req
+        .headers()
+        .get("X-Confirm-Wipe")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("yes"));
+    if !confirmed {
+        return bad_request("Deleting a domain requires the X-Confirm-Wipe: yes header");
+    }
+
+    let domain = domain.to_lowercase();
+    let conn = Connection::open_default()?;
+    let query_params = [sqlite::ValueParam::Text(&domain)];
+    conn.execute(
+        "DELETE FROM paragraphs WHERE source_domain = ?",
+        &query_params,
+    )?;
+    let deleted = conn
+        .execute("SELECT changes() AS changed", &[])?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("changed"))
+        .unwrap_or_default();
+    invalidate_search_index();
+    warn!("Deleted {} paragraphs from domain {}", deleted, domain);
+
+    json_response(http::StatusCode::OK, &DeleteResult { deleted })
+}
+
+/// Deletes the paragraphs of several domains in one transaction, so either all
+/// of them are deleted or, if any fails, none. Soft deletion only hides the
+/// paragraphs: every read goes through the `live_paragraphs` view.
+fn batch_delete_by_domain(req: Request, _params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+    let query: BatchDeleteByDomainQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse batch delete: {:?}", err);
+            return bad_request("Invalid batch delete");
+        }
+    };
+    if query.domains.is_empty() {
+        return bad_request("domains must not be empty");
+    }
+
+    let conn = Connection::open_default()?;
+    conn.execute("BEGIN", &[])?;
+    let results = query
+        .domains
+        .iter()
+        .map(|domain| -> Result<DomainDeleteResult> {
+            let domain = domain.to_lowercase();
+            let query_params = [sqlite::ValueParam::Text(&domain)];
+            let statement = if query.hard_delete {
+                "DELETE FROM paragraphs WHERE source_domain = ?"
+            } else {
+                "UPDATE paragraphs SET deleted_at = datetime('now') WHERE source_domain = ? AND deleted_at IS NULL"
+            };
+            conn.execute(statement, &query_params)?;
+            let deleted = conn
+                .execute("SELECT changes() AS changed", &[])?
+                .rows()
+                .next()
+                .and_then(|row| row.get::<i64>("changed"))
+                .unwrap_or_default();
+            Ok(DomainDeleteResult { domain, deleted })
+        })
+        .collect::<Result<Vec<DomainDeleteResult>>>();
+    let results = match results {
+        Ok(results) => {
+            conn.execute("COMMIT", &[])?;
+            results
+        }
+        Err(err) => {
+            conn.execute("ROLLBACK", &[])?;
+            return Err(err);
+        }
+    };
+    invalidate_search_index();
+    for result in &results {
+        warn!(
+            "Deleted {} paragraphs from domain {}",
+            result.deleted, result.domain
+        );
+    }
+
+    json_response(http::StatusCode::OK, &BatchDeleteResult { results })
+}
+
+/// Groups the paragraphs whose text is identical, e.g. syndicated content
+/// stored from several URLs, oldest first within each group.
+fn get_text_duplicates(_req: Request, _params: Params) -> Result<Response> {
+    // Crawler ISO 8601 times and `datetime('now')` strings don't sort alike
+    // as text, so both are compared as Julian days.
+    let result = Connection::open_default()?.execute(
+        "SELECT reference, content_hash FROM paragraphs WHERE deleted_at IS NULL AND content_hash IN (SELECT content_hash FROM live_paragraphs GROUP BY content_hash HAVING COUNT(*) > 1) ORDER BY content_hash, julianday(loaded_at), rowid",
+        &[],
+    )?;
+    let mut duplicates: Vec<TextDuplicates> = vec![];
+    for row in result.rows() {
+        let (Some(reference), Some(hash)) = (
+            row.get::<&str>("reference"),
+            row.get::<&str>("content_hash"),
+        ) else {
+            continue;
+        };
+        match duplicates.last_mut() {
+            Some(group) if group.text_hash == hash => {
+                group.references.push(reference.to_owned());
+                group.count += 1;
+            }
+            _ => duplicates.push(TextDuplicates {
+                text_hash: hash.to_owned(),
+                references: vec![reference.to_owned()],
+                count: 1,
+            }),
+        }
+    }
+
+    json_response(http::StatusCode::OK, &duplicates)
+}
+
+/// Deletes every paragraph whose text is identical to that of an older one.
+fn delete_text_duplicates(req: Request, _params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+    let query: DedupQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse text-duplicates query: {:?}", err);
+            return bad_request("Invalid text-duplicates query");
+        }
+    };
+    if query.keep != "first" {
+        return bad_request("keep must be first");
+    }
+
+    let conn = Connection::open_default()?;
+    // Rows without loaded_at predate it being recorded, so they sort first
+    // as the oldest. Like in `get_text_duplicates`, times are compared as
+    // Julian days.
+    conn.execute(
+        "DELETE FROM paragraphs WHERE reference IN (SELECT reference FROM (SELECT reference, ROW_NUMBER() OVER (PARTITION BY content_hash ORDER BY julianday(loaded_at), rowid) AS position FROM paragraphs WHERE content_hash IS NOT NULL AND deleted_at IS NULL) WHERE position > 1)",
+        &[],
+    )?;
+    let deleted = conn
+        .execute("SELECT changes() AS changed", &[])?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("changed"))
+        .unwrap_or_default();
+    invalidate_search_index();
+    warn!("Deleted {} paragraphs duplicating older ones", deleted);
+
+    json_response(http::StatusCode::OK, &DeleteResult { deleted })
+}
+
+fn get_orphan_paragraphs(req: Request, _params: Params) -> Result<Response> {
+    let query: ListQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse orphans query: {:?}", err);
+            return bad_request("Invalid orphans query");
+        }
+    };
+
+    let query_params = [sqlite::ValueParam::Integer(query.limit.unwrap_or(50))];
+    let orphans = Connection::open_default()?
+        .execute(
+            "SELECT * FROM live_paragraphs WHERE IFNULL(retrieval_count, 0) = 0 LIMIT ?",
+            &query_params,
+        )?
+        .rows()
+        .map(Paragraph::try_from)
+        .collect::<Result<Vec<Paragraph>>>()?;
+
+    json_response(http::StatusCode::OK, &orphans)
+}
+
+fn get_paragraphs_by_tag(req: Request, params: Params) -> Result<Response> {
+    let tag = match params.get("tag") {
+        Some(tag) => tag,
+        None => return not_found(),
+    };
+    let query: ListQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse by-tag query: {:?}", err);
+            return bad_request("Invalid by-tag query");
+        }
+    };
+
+    let pattern = tag_pattern(tag)?;
+    let conn = Connection::open_default()?;
+
+    let count_params = [sqlite::ValueParam::Text(&pattern)];
+    let total = conn
+        .execute(
+            "SELECT COUNT(*) AS total FROM live_paragraphs WHERE tags LIKE ? ESCAPE '\\'",
+            &count_params,
+        )?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("total"))
+        .unwrap_or_default();
+
+    let query_params = [
+        sqlite::ValueParam::Text(&pattern),
+        sqlite::ValueParam::Integer(query.limit.unwrap_or(50)),
+        sqlite::ValueParam::Integer(query.offset.unwrap_or(0)),
+    ];
+    let items = conn
+        .execute(
+            "SELECT * FROM live_paragraphs WHERE tags LIKE ? ESCAPE '\\' ORDER BY IFNULL(retrieval_count, 0) DESC LIMIT ? OFFSET ?",
+            &query_params,
+        )?
+        .rows()
+        .map(Paragraph::try_from)
+        .collect::<Result<Vec<Paragraph>>>()?;
+
+    json_response(http::StatusCode::OK, &ParagraphPage { total, items })
+}
+
+fn get_paragraph_record(req: Request, params: Params) -> Result<Response> {
+    let query: CorpusFitQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse paragraph query: {:?}", err);
+            return bad_request("Invalid paragraph query");
+        }
+    };
+    match params.get("reference") {
+        Some(reference) => paragraph_response(reference, query.with_corpus_fit),
+        None => not_found(),
+    }
+}
+
+/// Returns the stored embedding of a paragraph, the counterpart of
+/// `PUT /embeddings/:reference/embedding`.
+fn get_paragraph_embedding(_req: Request, params: Params) -> Result<Response> {
+    let reference = match params.get("reference") {
+        Some(reference) => reference,
+        None => return not_found(),
+    };
+
+    let query_params = [sqlite::ValueParam::Text(reference)];
+    let result = Connection::open_default()?.execute(
+        "SELECT embedding, compressed, format, model FROM live_paragraphs WHERE reference = ?",
+        &query_params,
+    )?;
+    let row = match result.rows().next() {
+        Some(row) => row,
+        None => return not_found(),
+    };
+    let embedding = embedding_from_row(&row)?;
+
+    json_response(
+        http::StatusCode::OK,
+        &StoredEmbeddingResult {
+            reference: reference.to_owned(),
+            dim: embedding.len(),
+            model: row
+                .get::<&str>("model")
+                .unwrap_or(EMBEDDING_MODEL)
+                .to_owned(),
+            embedding,
+        },
+    )
+}
+
+fn get_paragraph_by_url(req: Request, _params: Params) -> Result<Response> {
+    // serde_qs percent-decodes the value, so references containing slashes
+    // can be looked up without mangling the path.
+    let query: UrlQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse url query: {:?}", err);
+            return bad_request("Missing url query parameter");
+        }
+    };
+
+    paragraph_response(&query.url, query.with_corpus_fit)
+}
+
+/// Stores whether a paragraph is relevant to a query, replacing any earlier
+/// judgment of the pair.
+fn record_feedback(req: Request, _params: Params) -> Result<Response> {
+    let feedback: Feedback = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(f) => f,
+        Err(err) => {
+            error!("Failed to parse feedback: {:?}", err);
+            return bad_request("Invalid feedback");
+        }
+    };
+
+    let conn = Connection::open_default()?;
+    if !paragraph_exists(&conn, &feedback.reference)? {
+        return not_found();
+    }
+    let query_params = [
+        sqlite::ValueParam::Text(&feedback.query),
+        sqlite::ValueParam::Text(&feedback.reference),
+        sqlite::ValueParam::Integer(feedback.relevant.into()),
+    ];
+    conn.execute(
+        "INSERT OR REPLACE INTO search_feedback (query, reference, relevant, judged_at) VALUES (?, ?, ?, datetime('now'))",
+        &query_params,
+    )?;
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::NO_CONTENT)
+        .body(None)?)
+}
+
+/// Evaluates the current search against the feedback stored for a query.
+fn get_feedback_metrics(req: Request, _params: Params) -> Result<Response> {
+    let query: FeedbackMetricsQuery =
+        match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+            Ok(q) => q,
+            Err(err) => {
+                error!("Failed to parse feedback metrics query: {:?}", err);
+                return bad_request("Missing query query parameter");
+            }
+        };
+    if !(1..=100).contains(&query.k) {
+        return bad_request("k must be between 1 and 100");
+    }
+
+    let query_params = [sqlite::ValueParam::Text(&query.query)];
+    let judgments: HashMap<String, bool> = Connection::open_default()?
+        .execute(
+            "SELECT reference, relevant FROM search_feedback WHERE query = ?",
+            &query_params,
+        )?
+        .rows()
+        .filter_map(|row| {
+            Some((
+                row.get::<&str>("reference")?.to_owned(),
+                row.get::<i64>("relevant")? != 0,
+            ))
+        })
+        .collect();
+    let relevant_count = judgments.values().filter(|relevant| **relevant).count();
+
+    // Not recorded as retrievals or searches, so evaluating doesn't skew them.
+    let mut results = get_similar_paragraphs(
+        &query.query,
+        &CompareFilter::default(),
+        &RankingOptions::default(),
+    )?
+    .results;
+    results.truncate(query.k);
+    let relevant_retrieved = results
+        .iter()
+        .filter(|r| judgments.get(&r.paragraph.reference) == Some(&true))
+        .count();
+
+    json_response(
+        http::StatusCode::OK,
+        &FeedbackMetrics {
+            precision_at_k: relevant_retrieved as f32 / query.k as f32,
+            recall_at_k: if relevant_count == 0 {
+                0.0
+            } else {
+                relevant_retrieved as f32 / relevant_count as f32
+            },
+            k: query.k,
+            judged_count: judgments.len(),
+        },
+    )
+}
+
+/// Scores the search results for a test set of questions against the
+/// references expected for each.
+fn score_dataset(req: Request, _params: Params) -> Result<Response> {
+    let dataset: ScoreDatasetQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(d) => d,
+        Err(err) => {
+            error!("Failed to parse dataset: {:?}", err);
+            return bad_request("Invalid dataset");
+        }
+    };
+    if dataset.queries.is_empty() {
+        return bad_request("queries must not be empty");
+    }
+    if !(1..=100).contains(&dataset.k) {
+        return bad_request("k must be between 1 and 100");
+    }
+
+    let mut hits = 0;
+    let mut reciprocal_ranks = 0.0;
+    for query in &dataset.queries {
+        // Not recorded as retrievals or searches, so evaluating doesn't skew them.
+        let results = get_similar_paragraphs(
+            &query.question,
+            &CompareFilter::default(),
+            &RankingOptions::default(),
+        )?
+        .results;
+        let first_hit = results
+            .iter()
+            .take(dataset.k)
+            .position(|r| query.expected_references.contains(&r.paragraph.reference));
+        if let Some(position) = first_hit {
+            hits += 1;
+            reciprocal_ranks += 1.0 / (position + 1) as f32;
+        }
+    }
+
+    let query_count = dataset.queries.len();
+    json_response(
+        http::StatusCode::OK,
+        &DatasetScore {
+            mean_hit_at_k: hits as f32 / query_count as f32,
+            mean_reciprocal_rank: reciprocal_ranks / query_count as f32,
+            k: dataset.k,
+            query_count,
+        },
+    )
+}
+
+fn get_paragraph_by_id(req: Request, params: Params) -> Result<Response> {
+    let page_id = match params.get("page_id") {
+        Some(page_id) => page_id,
+        None => return not_found(),
+    };
+    let query: CorpusFitQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse paragraph query: {:?}", err);
+            return bad_request("Invalid paragraph query");
+        }
+    };
+
+    let query_params = [sqlite::ValueParam::Text(page_id)];
+    let reference = Connection::open_default()?
+        .execute(
+            "SELECT reference FROM live_paragraphs WHERE page_id = ?",
+            &query_params,
+        )?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<&str>("reference").map(str::to_owned));
+    match reference {
+        Some(reference) => paragraph_response(&reference, query.with_corpus_fit),
+        None => not_found(),
+    }
+}
+
+fn get_search_history(req: Request, _params: Params) -> Result<Response> {
+    let query: HistoryQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse history query: {:?}", err);
+            return bad_request("order must be one of: popular, recent");
+        }
+    };
+
+    let sql_query = match query.order {
+        HistoryOrder::Popular => {
+            "SELECT sentence, count, last_queried FROM search_history ORDER BY count DESC, last_queried DESC LIMIT ?"
+        }
+        HistoryOrder::Recent => {
+            "SELECT sentence, count, last_queried FROM search_history ORDER BY last_queried DESC LIMIT ?"
+        }
+    };
+    let query_params = [sqlite::ValueParam::Integer(query.limit.unwrap_or(20))];
+    let history = Connection::open_default()?
+        .execute(sql_query, &query_params)?
+        .rows()
+        .map(|row| -> Result<SearchHistoryEntry> {
+            Ok(SearchHistoryEntry {
+                sentence: row
+                    .get::<&str>("sentence")
+                    .context("sentence column is empty")?
+                    .to_owned(),
+                count: row.get::<i64>("count").unwrap_or(1),
+                last_queried: row
+                    .get::<&str>("last_queried")
+                    .unwrap_or_default()
+                    .to_owned(),
+            })
+        })
+        .collect::<Result<Vec<SearchHistoryEntry>>>()?;
+
+    json_response(http::StatusCode::OK, &history)
+}
+
+/// Most frequent searches of the last days, for autocomplete and trending
+/// searches.
+fn get_hot_queries(req: Request, _params: Params) -> Result<Response> {
+    let query: HotQueriesQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse hot queries query: {:?}", err);
+            return bad_request("Invalid hot queries query");
+        }
+    };
+    let days = match parse_days(&query.since) {
+        Some(days) => days,
+        None => return bad_request("since must be a number of days, e.g. 7d"),
+    };
+
+    let modifier = format!("-{} days", days);
+    let query_params = [
+        sqlite::ValueParam::Text(&modifier),
+        sqlite::ValueParam::Integer(query.limit.unwrap_or(10)),
+    ];
+    let hot_queries = Connection::open_default()?
+        .execute(
+            "SELECT sentence, count, last_queried FROM search_history WHERE last_queried > datetime('now', ?) ORDER BY count DESC LIMIT ?",
+            &query_params,
+        )?
+        .rows()
+        .filter_map(|row| {
+            Some(HotQuery {
+                query: row.get::<&str>("sentence")?.to_owned(),
+                count: row.get::<i64>("count").unwrap_or(1),
+                last_queried: row
+                    .get::<&str>("last_queried")
+                    .unwrap_or_default()
+                    .to_owned(),
+            })
+        })
+        .collect::<Vec<HotQuery>>();
+
+    json_response(http::StatusCode::OK, &hot_queries)
+}
+
+fn get_top_domains(req: Request, _params: Params) -> Result<Response> {
+    let query: ListQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse top-domains query: {:?}", err);
+            return bad_request("Invalid top-domains query");
+        }
+    };
+
+    let query_params = [sqlite::ValueParam::Integer(query.limit.unwrap_or(10))];
+    let domains = Connection::open_default()?
+        .execute(
+            "SELECT source_domain, COUNT(*) AS cnt FROM live_paragraphs WHERE source_domain IS NOT NULL GROUP BY source_domain ORDER BY cnt DESC LIMIT ?",
+            &query_params,
+        )?
+        .rows()
+        .map(|row| -> Result<DomainCount> {
+            Ok(DomainCount {
+                domain: row
+                    .get::<&str>("source_domain")
+                    .context("source_domain column is empty")?
+                    .to_owned(),
+                count: row.get::<i64>("cnt").unwrap_or_default(),
+            })
+        })
+        .collect::<Result<Vec<DomainCount>>>()?;
+
+    json_response(http::StatusCode::OK, &domains)
+}
+
+/// Summarizes the pairwise similarities of a random sample of the stored
+/// embeddings. High similarities mean a narrow corpus, low ones a diverse one.
+fn get_diversity_score(_req: Request, _params: Params) -> Result<Response> {
+    // Half a million pairs, which is about as many as a request should compare.
+    const MAX_SAMPLE_SIZE: i64 = 1000;
+
+    let result = Connection::open_default()?.execute(
+        "SELECT reference, embedding, compressed, format FROM live_paragraphs ORDER BY RANDOM() LIMIT ?",
+        &[sqlite::ValueParam::Integer(MAX_SAMPLE_SIZE)],
+    )?;
+    let mut sample: Vec<Vec<f32>> = vec![];
+    for row in result.rows() {
+        match embedding_from_row(&row) {
+            Ok(embedding) => sample.push(embedding),
+            Err(err) => error!(
+                "Skipping {:?} in diversity score: {:?}",
+                row.get::<&str>("reference"),
+                err
+            ),
+        }
+    }
+    if sample.len() < 2 {
+        return not_found();
+    }
+
+    let mut similarities: Vec<f32> = vec![];
+    for (i, a) in sample.iter().enumerate() {
+        for b in &sample[i + 1..] {
+            similarities.push(cosine_similarity(a, b));
+        }
+    }
+    let count = similarities.len() as f32;
+    let mean = similarities.iter().sum::<f32>() / count;
+    let variance = similarities.iter().map(|s| (s - mean).powi(2)).sum::<f32>() / count;
+
+    json_response(
+        http::StatusCode::OK,
+        &DiversityScore {
+            mean_pairwise_similarity: mean,
+            std_dev: variance.sqrt(),
+            min: similarities.iter().copied().fold(f32::INFINITY, f32::min),
+            max: similarities
+                .iter()
+                .copied()
+                .fold(f32::NEG_INFINITY, f32::max),
+            sample_size: sample.len(),
+        },
+    )
+}
+
+/// Finds paragraphs whose embedding is far from the centroid of their domain,
+/// which may point at ingestion errors.
+fn get_anomalies(req: Request, _params: Params) -> Result<Response> {
+    let query: AnomalyQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse anomalies query: {:?}", err);
+            return bad_request("Invalid anomalies query");
+        }
+    };
+
+    let result = Connection::open_default()?.execute(
+        "SELECT reference, source_domain, embedding, compressed, format FROM live_paragraphs WHERE source_domain IS NOT NULL",
+        &[],
+    )?;
+    // References and embeddings of each domain's paragraphs, in the same order.
+    let mut domains: HashMap<&str, (Vec<&str>, Vec<Vec<f32>>)> = HashMap::new();
+    for row in result.rows() {
+        let (Some(reference), Some(domain)) = (
+            row.get::<&str>("reference"),
+            row.get::<&str>("source_domain"),
+        ) else {
+            continue;
+        };
+        match embedding_from_row(&row) {
+            Ok(embedding) => {
+                let (references, embeddings) = domains.entry(domain).or_default();
+                references.push(reference);
+                embeddings.push(embedding);
+            }
+            Err(err) => error!("Skipping {} in anomaly detection: {:?}", reference, err),
+        }
+    }
+
+    let mut anomalies: Vec<Anomaly> = vec![];
+    for (references, embeddings) in domains.values() {
+        let centroid = merge_embeddings(embeddings);
+        for (reference, embedding) in references.iter().zip(embeddings) {
+            let domain_similarity = cosine_similarity(embedding, &centroid);
+            if domain_similarity < query.threshold {
+                anomalies.push(Anomaly {
+                    reference: reference.to_string(),
+                    domain_similarity,
+                });
+            }
+        }
+    }
+    anomalies.sort_by(|a, b| a.domain_similarity.total_cmp(&b.domain_similarity));
+
+    json_response(http::StatusCode::OK, &anomalies)
+}
+
+fn create_corpus_snapshot(_req: Request, _params: Params) -> Result<Response> {
+    let records = get_compare_set(&CompareFilter::default())?;
+    if records.is_empty() {
+        return not_found();
+    }
+    let centroid = centroid(records.iter().map(|r| r.embedding.as_slice()));
+
+    let conn = Connection::open_default()?;
+    let snapshot_at = conn
+        .execute("SELECT datetime('now') AS now", &[])?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<&str>("now").map(str::to_owned))
+        .context("SQLite returned no time")?;
+    let blob = embedding_to_blob(&centroid)?;
+    let query_params = [
+        sqlite::ValueParam::Text(&snapshot_at),
+        sqlite::ValueParam::Blob(blob.as_slice()),
+        sqlite::ValueParam::Integer(records.len() as i64),
+    ];
+    conn.execute(
+        "INSERT OR REPLACE INTO corpus_snapshots (snapshot_at, centroid, paragraph_count) VALUES (?, ?, ?)",
+        &query_params,
+    )?;
+    info!("Stored corpus snapshot of {} paragraphs", records.len());
+
+    json_response(
+        http::StatusCode::CREATED,
+        &CorpusSnapshot {
+            snapshot_at,
+            paragraph_count: records.len() as i64,
+        },
+    )
+}
+
+/// Key-value store keys of embeddings snapshots start with this.
+const SNAPSHOT_KEY_PREFIX: &str = "embeddings_snapshots/";
+
+/// Saves every paragraph, embedding and metadata included, as CBOR to the
+/// default key-value store, overwriting a snapshot of the same name.
+fn save_embeddings_snapshot(req: Request, _params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+    let query: SnapshotEmbeddingsQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse snapshot: {:?}", err);
+            return bad_request("Invalid snapshot");
+        }
+    };
+    if !is_valid_snapshot_name(&query.name) {
+        return bad_request("name must be 1 to 100 letters, digits, '-', '_' or '.' characters");
+    }
+
+    let result = Connection::open_default()?.execute("SELECT * FROM live_paragraphs", &[])?;
+    let paragraphs = result
+        .rows()
+        .map(|row| SnapshotParagraph::try_from(&row))
+        .collect::<Result<Vec<SnapshotParagraph>>>()?;
+    let mut buffer = Vec::new();
+    ciborium::into_writer(&paragraphs, &mut buffer)?;
+    spin_sdk::key_value::Store::open_default()?
+        .set(&format!("{}{}", SNAPSHOT_KEY_PREFIX, query.name), &buffer)?;
+    info!(
+        "Saved {} paragraphs to snapshot {}",
+        paragraphs.len(),
+        query.name
+    );
+
+    json_response(
+        http::StatusCode::CREATED,
+        &EmbeddingsSnapshot {
+            name: query.name,
+            paragraph_count: paragraphs.len(),
+        },
+    )
+}
+
+fn get_embeddings_snapshots(_req: Request, _params: Params) -> Result<Response> {
+    let mut names: Vec<String> = spin_sdk::key_value::Store::open_default()?
+        .get_keys()?
+        .into_iter()
+        .filter_map(|key| key.strip_prefix(SNAPSHOT_KEY_PREFIX).map(str::to_owned))
+        .collect();
+    names.sort();
+
+    json_response(http::StatusCode::OK, &names)
+}
+
+/// Replaces all stored paragraphs with those of a snapshot, in one
+/// transaction so a failed restore leaves the paragraphs as they were.
+fn restore_embeddings_snapshot(req: Request, params: Params) -> Result<Response> {
+    if let Some(denied) = check_admin_key(&req)? {
+        return Ok(denied);
+    }
+    let Some(name) = params.get("name") else {
+        return not_found();
+    };
+    let store = spin_sdk::key_value::Store::open_default()?;
+    let key = format!("{}{}", SNAPSHOT_KEY_PREFIX, name);
+    if !store.exists(&key)? {
+        return not_found();
+    }
+    let paragraphs: Vec<SnapshotParagraph> = ciborium::from_reader(store.get(&key)?.as_slice())?;
+
+    let conn = Connection::open_default()?;
+    conn.execute("BEGIN", &[])?;
+    let restored = (|| -> Result<()> {
+        conn.execute("DELETE FROM paragraphs", &[])?;
+        for paragraph in &paragraphs {
+            paragraph.restore(&conn)?;
+        }
+        Ok(())
+    })();
+    match restored {
+        Ok(()) => conn.execute("COMMIT", &[])?,
+        Err(err) => {
+            conn.execute("ROLLBACK", &[])?;
+            return Err(err);
+        }
+    };
+    invalidate_search_index();
+    warn!(
+        "Restored {} paragraphs from snapshot {}",
+        paragraphs.len(),
+        name
+    );
+
+    json_response(
+        http::StatusCode::OK,
+        &EmbeddingsSnapshot {
+            name: name.to_owned(),
+            paragraph_count: paragraphs.len(),
+        },
+    )
+}
+
+fn is_valid_snapshot_name(name: &str) -> bool {
+    (1..=100).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Compares the current corpus centroid with the first snapshot taken at or
+/// after `since`.
+fn get_corpus_drift(req: Request, _params: Params) -> Result<Response> {
+    let query: DriftQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse drift query: {:?}", err);
+            return bad_request("Missing since query parameter");
+        }
+    };
+
+    let query_params = [sqlite::ValueParam::Text(&query.since)];
+    let result = Connection::open_default()?.execute(
+        "SELECT * FROM corpus_snapshots WHERE snapshot_at >= ? ORDER BY snapshot_at LIMIT 1",
+        &query_params,
+    )?;
+    let row = match result.rows().next() {
+        Some(row) => row,
+        None => return not_found(),
+    };
+    let snapshot_centroid: Vec<f32> = match row.get::<&ValueResult>("centroid") {
+        Some(ValueResult::Blob(b)) => serde_json::from_slice(b.as_slice())?,
+        _ => anyhow::bail!("Snapshot has no centroid"),
+    };
+
+    let records = get_compare_set(&CompareFilter::default())?;
+    let current_centroid = centroid(records.iter().map(|r| r.embedding.as_slice()));
+
+    json_response(
+        http::StatusCode::OK,
+        &CorpusDrift {
+            drift_score: cosine_similarity(&current_centroid, &snapshot_centroid),
+            snapshot_at: row
+                .get::<&str>("snapshot_at")
+                .unwrap_or_default()
+                .to_owned(),
+            current_count: records.len() as i64,
+            snapshot_count: row.get::<i64>("paragraph_count").unwrap_or_default(),
+        },
+    )
+}
+
+fn get_domain_coverage(req: Request, _params: Params) -> Result<Response> {
+    let query: CoverageQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse coverage query: {:?}", err);
+            return bad_request("Missing domain query parameter");
+        }
+    };
+
+    // Crawled pages are referenced by their title, so match them on the
+    // domain of their URL; segments are matched on their reference prefix.
+    let domain = query.domain.to_lowercase();
+    let pattern = format!("https://{}/%", escape_like(&domain));
+    let query_params = [
+        sqlite::ValueParam::Text(&domain),
+        sqlite::ValueParam::Text(&pattern),
+    ];
+    let indexed = Connection::open_default()?
+        .execute(
+            "SELECT COUNT(*) AS indexed FROM live_paragraphs WHERE source_domain = ? OR reference LIKE ? ESCAPE '\\'",
+            &query_params,
+        )?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("indexed"))
+        .unwrap_or_default();
+
+    let coverage_pct = query
+        .total
+        .filter(|total| *total > 0)
+        .map(|total| indexed as f64 / total as f64 * 100.0);
+
+    json_response(
+        http::StatusCode::OK,
+        &Coverage {
+            indexed,
+            total: query.total,
+            coverage_pct,
+        },
+    )
+}
+
+/// Responds with the paragraph and its version, and with `corpus_fit` also
+/// its similarity to the corpus centroid.
+fn paragraph_response(reference: &str, corpus_fit: bool) -> Result<Response> {
+    let conn = Connection::open_default()?;
+    let response = if corpus_fit {
+        let record = match find_paragraph_record(&conn, reference)? {
+            Some(record) => record,
+            None => return not_found(),
+        };
+        let corpus_similarity = cosine_similarity(&record.embedding, &corpus_centroid(&conn)?);
+        json_response(
+            http::StatusCode::OK,
+            &WithCorpusFit {
+                paragraph: record.into_paragraph(),
+                corpus_similarity,
+            },
+        )?
+    } else {
+        match get_paragraph(&conn, reference)? {
+            Some(paragraph) => json_response(http::StatusCode::OK, &paragraph)?,
+            None => return not_found(),
+        }
+    };
+    with_etag(response, paragraph_version(&conn, reference)?.unwrap_or(1))
+}
+
+/// Centroid of the latest corpus snapshot, or of the current corpus if none
+/// was taken.
+fn corpus_centroid(conn: &Connection) -> Result<Vec<f32>> {
+    let result = conn.execute(
+        "SELECT centroid FROM corpus_snapshots ORDER BY snapshot_at DESC LIMIT 1",
+        &[],
+    )?;
+    if let Some(ValueResult::Blob(b)) = result
+        .rows()
+        .next()
+        .and_then(|row| row.get::<&ValueResult>("centroid"))
+    {
+        return Ok(serde_json::from_slice(b.as_slice())?);
+    }
+    let records = get_compare_set(&CompareFilter::default())?;
+    Ok(centroid(records.iter().map(|r| r.embedding.as_slice())))
+}
+
+fn put_paragraph_embedding(req: Request, params: Params) -> Result<Response> {
+    let reference = match params.get("reference") {
+        Some(reference) => reference,
+        None => return not_found(),
+    };
+
+    let embedding_override: EmbeddingOverride = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(o) => o,
+        Err(err) => {
+            error!("Failed to parse embedding override: {:?}", err);
+            return bad_request("Invalid embedding override");
+        }
+    };
+
+    if embedding_override.embedding.len() != EMBEDDING_DIM {
+        return bad_request(&format!(
+            "Expected embedding of dimension {}, got {}",
+            EMBEDDING_DIM,
+            embedding_override.embedding.len()
+        ));
+    }
+    if !is_valid_embedding(&embedding_override.embedding) {
+        return bad_request("embedding must be finite and non-zero");
+    }
+
+    let conn = Connection::open_default()?;
+    let Some(version) = paragraph_version(&conn, reference)? else {
+        return not_found();
+    };
+    archive_paragraph_version(&conn, reference, version)?;
+
+    let stored = StoredEmbedding::encode(&l2_normalized(&embedding_override.embedding))?;
+    let query_params = [
+        sqlite::ValueParam::Blob(stored.blob.as_slice()),
+        sqlite::ValueParam::Integer(stored.compressed.into()),
+        sqlite::ValueParam::Text(stored.format.as_str()),
+        sqlite::ValueParam::Text(embedding_override.model.as_str()),
+        sqlite::ValueParam::Text(reference),
+    ];
+    conn.execute(
+        "UPDATE paragraphs SET embedding = ?, compressed = ?, format = ?, model = ?, version = IFNULL(version, 1) + 1 WHERE reference = ?",
+        &query_params,
+    )?;
+    invalidate_search_index();
+    info!(
+        "Overrode embedding for {} with model {}",
+        reference, embedding_override.model
+    );
+
+    json_response(
+        http::StatusCode::OK,
+        &EmbeddingOverrideResult {
+            reference: reference.to_owned(),
+            embedding_dim: embedding_override.embedding.len(),
+        },
+    )
+}
+
+/// Replaces the text of a paragraph, summarizing and embedding it again. The
+/// rest of its metadata is kept. The `If-Match` header must carry the current
+/// `version` of the paragraph, so that concurrent updates don't overwrite each
+/// other.
+fn patch_paragraph_text(req: Request, params: Params) -> Result<Response> {
+    let reference = match params.get("reference") {
+        Some(reference) => reference,
+        None => return not_found(),
+    };
+    let expected_version = match if_match_version(&req) {
+        Some(version) => version,
+        None => {
+            return Ok(http::Response::builder()
+                .status(http::StatusCode::PRECONDITION_REQUIRED)
+                .body(Some(
+                    "If-Match header with the paragraph version is required".into(),
+                ))?)
+        }
+    };
+
+    let update: TextUpdate = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(u) => u,
+        Err(err) => {
+            error!("Failed to parse text update: {:?}", err);
+            return bad_request("Invalid text update");
+        }
+    };
+
+    let conn = Connection::open_default()?;
+    let query_params = [sqlite::ValueParam::Text(reference)];
+    let (stored_hash, version) = match conn
+        .execute(
+            "SELECT content_hash, IFNULL(version, 1) AS version FROM live_paragraphs WHERE reference = ?",
+            &query_params,
+        )?
+        .rows()
+        .next()
+    {
+        Some(row) => (
+            row.get::<&str>("content_hash").map(str::to_owned),
+            row.get::<i64>("version").unwrap_or(1),
+        ),
+        None => return not_found(),
+    };
+    if version != expected_version {
+        return version_conflict(version);
+    }
+    let hash = content_hash(&update.text);
+    if stored_hash.as_deref() == Some(hash.as_str()) {
+        return with_etag(
+            json_response(http::StatusCode::OK, &TextUpdateResult { unchanged: true })?,
+            version,
+        );
+    }
+
+    let summary = summarize_text(&update.text, SUMMARY_SENTENCES)?;
+    let embedding = generate_normalized_embeddings(&[summary.summary.as_str()])?
+        .embeddings
+        .into_iter()
+        .next()
+        .context("LLM returned no embedding")?;
+    let stored = StoredEmbedding::encode(&embedding)?;
+    let keywords = serde_json::to_string(&extract_keywords(
+        &update.text,
+        KEYWORDS_PER_PARAGRAPH,
+        &DocumentFrequencies::load(&conn)?,
+    ))?;
+
+    let query_params = [
+        sqlite::ValueParam::Text(&update.text),
+        sqlite::ValueParam::Blob(stored.blob.as_slice()),
+        sqlite::ValueParam::Integer(stored.compressed.into()),
+        sqlite::ValueParam::Text(stored.format.as_str()),
+        sqlite::ValueParam::Text(EMBEDDING_MODEL),
+        sqlite::ValueParam::Integer(word_count(&update.text)),
+        sqlite::ValueParam::Text(&hash),
+        sqlite::ValueParam::Integer(simhash(&update.text) as i64),
+        sqlite::ValueParam::Integer(sentence_count(&update.text)),
+        sqlite::ValueParam::Text(&keywords),
+        sqlite::ValueParam::Text(&summary.summary),
+        sqlite::ValueParam::Text(&summary.prompt_hash),
+        sqlite::ValueParam::Real(summary.confidence.into()),
+        sqlite::ValueParam::Text(reference),
+        sqlite::ValueParam::Integer(version),
+    ];
+    archive_paragraph_version(&conn, reference, version)?;
+    // Checking the version again guards against updates made while the LLM
+    // was running.
+    conn.execute(
+        "UPDATE paragraphs SET text = ?, embedding = ?, compressed = ?, format = ?, model = ?, word_count = ?, content_hash = ?, simhash = ?, sentence_count = ?, keywords_extracted = ?, summary = ?, prompt_hash = ?, summarization_confidence = ?, sentence_embeddings = NULL, version = IFNULL(version, 1) + 1
+        WHERE reference = ? AND IFNULL(version, 1) = ?",
+        &query_params,
+    )?;
+    let updated = conn
+        .execute("SELECT changes() AS changed", &[])?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("changed"))
+        .unwrap_or_default();
+    if updated == 0 {
+        return version_conflict(paragraph_version(&conn, reference)?.unwrap_or(version));
+    }
+    invalidate_search_index();
+    info!("Updated text of {} to version {}", reference, version + 1);
+
+    with_etag(
+        http::Response::builder()
+            .status(http::StatusCode::NO_CONTENT)
+            .body(None)?,
+        version + 1,
+    )
+}
+
+/// Parses the version in an `If-Match` header, written as an entity tag like
+/// `"3"` or as a bare number.
+fn if_match_version(req: &Request) -> Option<i64> {
+    let value = req.headers().get("If-Match")?.to_str().ok()?.trim();
+    value
+        .trim_start_matches("W/")
+        .trim_matches('"')
+        .parse()
+        .ok()
+}
+
+/// Copies the given version of a paragraph to `paragraph_versions` before it
+/// is overwritten. A version already archived is kept as it is.
+fn archive_paragraph_version(conn: &impl Database, reference: &str, version: i64) -> Result<()> {
+    let query_params = [
+        sqlite::ValueParam::Text(reference),
+        sqlite::ValueParam::Integer(version),
+    ];
+    conn.execute(
+        "INSERT OR IGNORE INTO paragraph_versions (page_id, version, text, summary, embedding, compressed, format, changed_at)
+        SELECT page_id, IFNULL(version, 1), text, summary, embedding, compressed, format, datetime('now') FROM live_paragraphs WHERE reference = ? AND IFNULL(version, 1) = ?",
+        &query_params,
+    )?;
+    Ok(())
+}
+
+/// Archives the stored version of a paragraph an ingest is about to replace,
+/// if the paragraph is stored already.
+fn archive_replaced_paragraph(conn: &impl Database, reference: &str) -> Result<()> {
+    if let Some(version) = paragraph_version(conn, reference)? {
+        archive_paragraph_version(conn, reference, version)?;
+    }
+    Ok(())
+}
+
+/// Lists the earlier versions of a paragraph archived by ingests, `PATCH` and
+/// `PUT`, oldest first.
+fn get_paragraph_timeline(_req: Request, params: Params) -> Result<Response> {
+    let reference = match params.get("reference") {
+        Some(reference) => reference,
+        None => return not_found(),
+    };
+
+    let conn = Connection::open_default()?;
+    let Some(current_version) = paragraph_version(&conn, reference)? else {
+        return not_found();
+    };
+    let query_params = [sqlite::ValueParam::Text(reference)];
+    let versions = conn
+        .execute(
+            "SELECT v.version, v.text, v.summary, v.changed_at FROM paragraph_versions v JOIN live_paragraphs p ON p.page_id = v.page_id WHERE p.reference = ? ORDER BY v.version",
+            &query_params,
+        )?
+        .rows()
+        .map(|row| ParagraphVersion::from_row(&row))
+        .collect();
+
+    json_response(
+        http::StatusCode::OK,
+        &ParagraphTimeline {
+            reference: reference.to_owned(),
+            current_version,
+            versions,
+        },
+    )
+}
+
+/// Returns one archived version of a paragraph, including its embedding.
+fn get_paragraph_timeline_version(_req: Request, params: Params) -> Result<Response> {
+    let (Some(reference), Some(version)) = (params.get("reference"), params.get("version")) else {
+        return not_found();
+    };
+    let Ok(version) = version.parse::<i64>() else {
+        return bad_request("version must be a number");
+    };
+
+    let query_params = [
+        sqlite::ValueParam::Text(reference),
+        sqlite::ValueParam::Integer(version),
+    ];
+    let result = Connection::open_default()?.execute(
+        "SELECT v.* FROM paragraph_versions v JOIN live_paragraphs p ON p.page_id = v.page_id WHERE p.reference = ? AND v.version = ?",
+        &query_params,
+    )?;
+    let row = match result.rows().next() {
+        Some(row) => row,
+        None => return not_found(),
+    };
+
+    json_response(
+        http::StatusCode::OK,
+        &ArchivedParagraph {
+            version: ParagraphVersion::from_row(&row),
+            embedding: embedding_from_row(&row)?,
+        },
+    )
+}
+
+/// Runs SQL statements, so that the functions needing nothing else of the
+/// connection can be tested without the Spin host.
+trait Database {
+    fn execute(
+        &self,
+        query: &str,
+        parameters: &[sqlite::ValueParam],
+    ) -> Result<sqlite::QueryResult>;
+}
+
+impl Database for Connection {
+    fn execute(
+        &self,
+        query: &str,
+        parameters: &[sqlite::ValueParam],
+    ) -> Result<sqlite::QueryResult> {
+        Ok(Connection::execute(self, query, parameters)?)
+    }
+}
+
+fn paragraph_version(conn: &impl Database, reference: &str) -> Result<Option<i64>> {
+    let query_params = [sqlite::ValueParam::Text(reference)];
+    let version = conn
+        .execute(
+            "SELECT IFNULL(version, 1) AS version FROM live_paragraphs WHERE reference = ?",
+            &query_params,
+        )?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("version"));
+    Ok(version)
+}
+
+fn version_conflict(current_version: i64) -> Result<Response> {
+    with_etag(
+        http::Response::builder()
+            .status(http::StatusCode::CONFLICT)
+            .body(Some("Paragraph was changed, fetch it again".into()))?,
+        current_version,
+    )
+}
+
+/// Sets the `ETag` header to the paragraph version, for use in `If-Match`.
+fn with_etag(mut response: Response, version: i64) -> Result<Response> {
+    response.headers_mut().insert(
+        "ETag",
+        http::header::HeaderValue::from_str(&format!("\"{}\"", version))?,
+    );
+    Ok(response)
+}
+
+fn compare_texts(req: Request, _params: Params) -> Result<Response> {
+    let query: CompareQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse compare query: {:?}", err);
+            return bad_request("Invalid compare query");
+        }
+    };
+
+    let embeddings =
+        generate_normalized_embeddings(&[query.text_a.as_str(), query.text_b.as_str()])?.embeddings;
+    let (embedding_a, embedding_b) = match embeddings.as_slice() {
+        [a, b] => (a, b),
+        _ => anyhow::bail!("Expected 2 embeddings, got {}", embeddings.len()),
+    };
+
+    json_response(
+        http::StatusCode::OK,
+        &CompareResult {
+            similarity: cosine_similarity(embedding_a, embedding_b),
+            embedding_a_dim: embedding_a.len(),
+            embedding_b_dim: embedding_b.len(),
+            model: EMBEDDING_MODEL,
+        },
+    )
+}
+
+/// Returns the share of the dot product each embedding dimension contributes
+/// when comparing the query with a stored paragraph or another text, for
+/// rendering as a heatmap.
+fn explain_cosine(req: Request, _params: Params) -> Result<Response> {
+    let query: ExplainCosineQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse explain-cosine query: {:?}", err);
+            return bad_request("Invalid explain-cosine query");
+        }
+    };
+
+    let other = match (&query.reference, &query.text) {
+        (Some(reference), None) => {
+            match find_paragraph_record(&Connection::open_default()?, reference)? {
+                Some(record) => record.embedding,
+                None => return not_found(),
+            }
+        }
+        (None, Some(text)) => generate_normalized_embeddings(&[text.as_str()])?
+            .embeddings
+            .into_iter()
+            .next()
+            .context("LLM returned no embedding")?,
+        _ => return bad_request("Pass either reference or text"),
+    };
+    let embedded_query = embed_sentence(&query.query)?;
+
+    let products: Vec<f32> = embedded_query
+        .iter()
+        .zip(other.iter())
+        .map(|(x, y)| x * y)
+        .collect();
+    let dot_product = products.iter().sum::<f32>();
+    let contributions = if dot_product == 0.0 {
+        vec![0.0; products.len()]
+    } else {
+        products.iter().map(|p| p / dot_product).collect()
+    };
+
+    json_response(
+        http::StatusCode::OK,
+        &CosineExplanation {
+            contributions,
+            similarity: cosine_similarity(&embedded_query, &other),
+        },
+    )
+}
+
+/// Embeds the texts without storing them, for clients managing their own
+/// storage.
+fn embed_texts(req: Request, _params: Params) -> Result<Response> {
+    let body = body_bytes(&req);
+    if body.len() > MAX_BODY_BYTES {
+        return Ok(http::Response::builder()
+            .status(http::StatusCode::PAYLOAD_TOO_LARGE)
+            .body(Some(
+                format!("Body must not exceed {} bytes", MAX_BODY_BYTES).into(),
+            ))?);
+    }
+    let query: EmbedQuery = match serde_json::from_slice(body) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse embed query: {:?}", err);
+            return bad_request("Invalid embed query");
+        }
+    };
+    if query.texts.is_empty() {
+        return bad_request("texts must not be empty");
+    }
+
+    let texts: Vec<&str> = query.texts.iter().map(String::as_str).collect();
+    let mut embeddings = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+        embeddings.extend(generate_normalized_embeddings(batch)?.embeddings);
+    }
+    if embeddings.len() != texts.len() {
+        anyhow::bail!(
+            "Expected {} embeddings, got {}",
+            texts.len(),
+            embeddings.len()
+        );
+    }
+
+    json_response(
+        http::StatusCode::OK,
+        &EmbedResult {
+            embeddings,
+            model: EMBEDDING_MODEL,
+            dim: EMBEDDING_DIM,
+        },
+    )
+}
+
+/// Zero-shot classification: picks the category whose label embedding is the
+/// most similar to the paragraph.
+fn classify_paragraph(req: Request, _params: Params) -> Result<Response> {
+    let query: ClassifyQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse classify query: {:?}", err);
+            return bad_request("Invalid classify query");
+        }
+    };
+    if query.categories.is_empty() {
+        return bad_request("categories must not be empty");
+    }
+
+    let record = match find_paragraph_record(&Connection::open_default()?, &query.reference)? {
+        Some(record) => record,
+        None => return not_found(),
+    };
+    let labels: Vec<&str> = query.categories.iter().map(String::as_str).collect();
+    let label_embeddings = generate_normalized_embeddings(&labels)?.embeddings;
+    if label_embeddings.len() != labels.len() {
+        anyhow::bail!(
+            "Expected {} embeddings, got {}",
+            labels.len(),
+            label_embeddings.len()
+        );
+    }
+
+    let scores: HashMap<String, f32> = query
+        .categories
+        .into_iter()
+        .zip(label_embeddings.iter())
+        .map(|(category, embedding)| (category, cosine_similarity(&record.embedding, embedding)))
+        .collect();
+    let category = scores
+        .iter()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(category, _)| category.clone())
+        .unwrap_or_default();
+
+    json_response(
+        http::StatusCode::OK,
+        &Classification {
+            reference: query.reference,
+            category,
+            scores,
+        },
+    )
+}
+
+fn get_similarity_matrix(req: Request, _params: Params) -> Result<Response> {
+    let query: MatrixQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse similarity matrix query: {:?}", err);
+            return bad_request("Invalid similarity matrix query");
+        }
+    };
+    let max_references: usize = variable("max_matrix_references", 50)?;
+    if query.references.is_empty() || query.references.len() > max_references {
+        return bad_request(&format!(
+            "references must contain between 1 and {} entries",
+            max_references
+        ));
+    }
+
+    let conn = Connection::open_default()?;
+    let mut embeddings = Vec::with_capacity(query.references.len());
+    for reference in &query.references {
+        match find_paragraph_record(&conn, reference)? {
+            Some(record) => embeddings.push(record.embedding),
+            None => {
+                return Ok(http::Response::builder()
+                    .status(http::StatusCode::NOT_FOUND)
+                    .body(Some(format!("Unknown reference {}", reference).into()))?)
+            }
+        }
+    }
+
+    let matrix = embeddings
+        .iter()
+        .map(|a| embeddings.iter().map(|b| cosine_similarity(a, b)).collect())
+        .collect();
+
+    json_response(
+        http::StatusCode::OK,
+        &SimilarityMatrix {
+            labels: query.references,
+            matrix,
+        },
+    )
+}
+
+fn explain_similar_paragraphs(req: Request, _params: Params) -> Result<Response> {
+    let format = match ResponseFormat::from_accept(&req) {
+        Some(format) => format,
+        None => return unsupported_media_type(),
+    };
+    let query: ExplainQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse explain query: {:?}", err);
+            return bad_request("Invalid explain query");
+        }
+    };
+
+    let paragraphs = get_compare_set(&CompareFilter::default())?;
+    let embedded_sentence = embed_sentence(&query.sentence)?;
+    record_search(&query.sentence);
+
+    let mut results: Vec<SimilarityResult> = paragraphs
+        .into_iter()
+        .map(|p| {
+            let top_contributing_dims = if query.full_explain {
+                Some(
+                    explain_similarity(&p.embedding, &embedded_sentence, query.top_dims)
+                        .into_iter()
+                        .map(|(dim, contribution)| DimensionContribution { dim, contribution })
+                        .collect(),
+                )
+            } else {
+                None
+            };
+            let similarity = cosine_similarity(p.embedding.as_ref(), embedded_sentence.as_ref());
+            SimilarityResult {
+                top_contributing_dims,
+                ..SimilarityResult::new(p.into_paragraph(), similarity)
+            }
+        })
+        .collect();
+
+    results.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+
+    formatted_response(
+        format,
+        http::StatusCode::OK,
+        &SimilarityResultSet {
+            sentence: Some(query.sentence),
+            model: Some(EMBEDDING_MODEL),
+            hypothetical_doc: None,
+            expanded_queries: None,
+            results,
+        },
+    )
+}
+
+/// Lightweight search for typeahead UIs: results carry the beginning of their
+/// text instead of all of it. Previews aren't counted as searches.
+fn preview_similar_paragraphs(req: Request, _params: Params) -> Result<Response> {
+    let query: PreviewQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse preview query: {:?}", err);
+            return bad_request("Missing q query parameter");
+        }
+    };
+    let preview_length: usize = variable("preview_length", 200)?;
+
+    let mut results = get_similar_paragraphs(
+        &query.q,
+        &CompareFilter::default(),
+        &RankingOptions::default(),
+    )?
+    .results;
+    results.truncate(query.top_k);
+
+    let previews: Vec<PreviewResult> = results
+        .into_iter()
+        .map(|r| PreviewResult {
+            preview: r.paragraph.text.chars().take(preview_length).collect(),
+            reference: r.paragraph.reference,
+            similarity: r.similarity,
+        })
+        .collect();
+
+    json_response(http::StatusCode::OK, &previews)
+}
+
+/// Suggests the keywords of the paragraphs most similar to the query that
+/// aren't in the query itself, most relevant paragraph and keyword first.
+fn suggest_related_keywords(req: Request, _params: Params) -> Result<Response> {
+    const RELATED_PARAGRAPHS: usize = 5;
+
+    let query: SuggestRelatedQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default())
+    {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse suggest-related query: {:?}", err);
+            return bad_request("Missing q query parameter");
+        }
+    };
+
+    let mut results = get_similar_paragraphs(
+        &query.q,
+        &CompareFilter::default(),
+        &RankingOptions::default(),
+    )?
+    .results;
+    results.truncate(RELATED_PARAGRAPHS);
+
+    let mut seen: HashSet<String> = keyword_terms(&query.q).collect();
+    let suggestions: Vec<String> = results
+        .into_iter()
+        .flat_map(|r| r.paragraph.keywords_extracted)
+        .filter(|keyword| seen.insert(keyword.to_lowercase()))
+        .collect();
+
+    json_response(http::StatusCode::OK, &RelatedSuggestions { suggestions })
+}
+
+/// "More like this, less like that": ranks paragraphs by their similarity to
+/// the query minus half their similarity to `exclude_reference`.
+fn get_similar_excluding(req: Request, _params: Params) -> Result<Response> {
+    const EXCLUDE_WEIGHT: f32 = 0.5;
+
+    let query: ExcludeQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse similar-to-query query: {:?}", err);
+            return bad_request("Missing q query parameter");
+        }
+    };
+
+    let excluded_embedding = match &query.exclude_reference {
+        Some(reference) => {
+            let conn = Connection::open_default()?;
+            match find_paragraph_record(&conn, reference)? {
+                Some(record) => Some(record.embedding),
+                None => return not_found(),
+            }
+        }
+        None => None,
+    };
+
+    let embedded_query = embed_sentence(&query.q)?;
+    let mut results: Vec<SimilarityResult> = get_compare_set(&CompareFilter::default())?
+        .into_iter()
+        .filter(|p| Some(&p.reference) != query.exclude_reference.as_ref())
+        .map(|p| {
+            let penalty = excluded_embedding
+                .as_ref()
+                .map_or(0.0, |excluded| cosine_similarity(excluded, &p.embedding));
+            let similarity =
+                cosine_similarity(&p.embedding, &embedded_query) - EXCLUDE_WEIGHT * penalty;
+            SimilarityResult::new(p.into_paragraph(), similarity)
+        })
+        .collect();
+    results.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+
+    json_response(
+        http::StatusCode::OK,
+        &ExcludeResultSet {
+            excluded_reference: query.exclude_reference,
+            results,
+        },
+    )
+}
+
+/// Returns every paragraph at least `radius` similar to the given one, rather
+/// than a fixed number of them.
+fn get_neighbors(req: Request, _params: Params) -> Result<Response> {
+    let query: NeighborsQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse neighbors query: {:?}", err);
+            return bad_request("Missing reference query parameter");
+        }
+    };
+    if !(-1.0..=1.0).contains(&query.radius) {
+        return bad_request("radius must be between -1 and 1");
+    }
+
+    let conn = Connection::open_default()?;
+    let embedding = match find_paragraph_record(&conn, &query.reference)? {
+        Some(record) => record.embedding,
+        None => return not_found(),
+    };
+
+    let mut neighbors: Vec<SimilarityResult> = get_compare_set(&CompareFilter::default())?
+        .into_iter()
+        .filter(|p| p.reference != query.reference)
+        .map(|p| (cosine_similarity(&p.embedding, &embedding), p))
+        .filter(|(similarity, _)| *similarity >= query.radius)
+        .map(|(similarity, p)| SimilarityResult::new(p.into_paragraph(), similarity))
+        .collect();
+    neighbors.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+
+    json_response(
+        http::StatusCode::OK,
+        &NeighborsResult {
+            count: neighbors.len(),
+            reference: query.reference,
+            radius: query.radius,
+            neighbors,
+        },
+    )
+}
+
+fn rerank_similar_paragraphs(req: Request, _params: Params) -> Result<Response> {
+    let query: RerankQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse rerank query: {:?}", err);
+            return bad_request("Invalid rerank query");
+        }
+    };
+
+    let mut candidates = get_similar_paragraphs(
+        &query.question,
+        &CompareFilter::default(),
+        &RankingOptions::default(),
+    )?
+    .results;
+    candidates.truncate(query.candidates);
+
+    let mut results: Vec<RerankResult> = candidates
+        .into_iter()
+        .map(|c| {
+            let llm_rerank_score = match rerank_score(&query.question, &c.paragraph.text) {
+                Ok(score) => score,
+                Err(err) => {
+                    error!("Failed to rerank {}: {:?}", c.paragraph.reference, err);
+                    0.0
+                }
+            };
+            RerankResult {
+                paragraph: c.paragraph,
+                semantic_score: c.similarity,
+                llm_rerank_score,
+            }
+        })
+        .collect();
+
+    results.sort_by(|a, b| {
+        b.llm_rerank_score
+            .total_cmp(&a.llm_rerank_score)
+            .then(b.semantic_score.total_cmp(&a.semantic_score))
+    });
+    results.truncate(query.top_k);
+
+    json_response(
+        http::StatusCode::OK,
+        &RerankResultSet {
+            question: query.question,
+            results,
+        },
+    )
+}
+
+fn rerank_score(question: &str, text: &str) -> Result<f32> {
+    const PROMPT: &str = r#"<s>[INST]<<SYS>>You are a search relevance assistant. Rate how relevant the document is to the question on a scale from 1 to 10, where 10 means the document fully answers the question. Answer with a single number only.<</SYS>>Question: {QUESTION} Document: {DOCUMENT} [/INST]"#;
+    // Keep long pages from overflowing the model context window.
+    const MAX_DOCUMENT_CHARS: usize = 2000;
+
+    let document: String = text.chars().take(MAX_DOCUMENT_CHARS).collect();
+    let prompt = PROMPT
+        .replace("{QUESTION}", question)
+        .replace("{DOCUMENT}", &document);
+    let inferencing_result = infer(Llama2Chat, &prompt)?;
+    parse_rerank_score(&inferencing_result.text)
+        .with_context(|| format!("No score in LLM response {:?}", inferencing_result.text))
+}
+
+fn parse_rerank_score(text: &str) -> Option<f32> {
+    let number: String = text
+        .trim_start_matches(|c: char| !c.is_ascii_digit())
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect();
+    number
+        .trim_end_matches('.')
+        .parse::<f32>()
+        .ok()
+        .map(|score| score.clamp(1.0, 10.0))
+}
+
+fn explain_corpus(_req: Request, _params: Params) -> Result<Response> {
+    const PROMPT: &str = r#"<s>[INST]<<SYS>>You are a professional summarization assistant. You are given excerpts sampled from a collection of documents. Describe in one paragraph what the collection is about.<</SYS>>What is this collection of documents about? {EXCERPTS} [/INST]"#;
+    const SAMPLE_SIZE: i64 = 20;
+    // Keep the prompt within the model context window.
+    const MAX_EXCERPT_CHARS: usize = 300;
+    const RATE_LIMIT_SECONDS: i64 = 60;
+
+    let conn = Connection::open_default()?;
+    let total_paragraphs = count_paragraphs(&conn)?;
+    if total_paragraphs == 0 {
+        return not_found();
+    }
+
+    if !acquire_rate_limit(&conn, "explain-corpus", RATE_LIMIT_SECONDS)? {
+        return Ok(http::Response::builder()
+            .status(http::StatusCode::TOO_MANY_REQUESTS)
+            .header("Retry-After", RATE_LIMIT_SECONDS.to_string())
+            .body(Some("The corpus can be explained once a minute".into()))?);
+    }
+
+    let query_params = [sqlite::ValueParam::Integer(SAMPLE_SIZE)];
+    let sample = conn.execute(
+        "SELECT text FROM live_paragraphs ORDER BY RANDOM() LIMIT ?",
+        &query_params,
+    )?;
+    let excerpts: Vec<String> = sample
+        .rows()
+        .filter_map(|row| row.get::<&str>("text"))
+        .map(|text| text.chars().take(MAX_EXCERPT_CHARS).collect())
+        .collect();
+
+    let prompt = PROMPT.replace("{EXCERPTS}", &excerpts.join("\n\n"));
+    let inferencing_result = infer(Llama2Chat, &prompt)?;
+
+    json_response(
+        http::StatusCode::OK,
+        &CorpusSummary {
+            corpus_summary: inferencing_result.text.trim().to_owned(),
+            sample_size: excerpts.len(),
+            total_paragraphs,
+        },
+    )
+}
+
+fn generate_quiz(req: Request, _params: Params) -> Result<Response> {
+    const PROMPT: &str = r#"<s>[INST]<<SYS>>You are a teacher writing reading comprehension tests. Reply with the question only, without its answer.<</SYS>>Generate one comprehension question about the following text: {TEXT} [/INST]"#;
+    // Keep long pages from overflowing the model context window.
+    const MAX_TEXT_CHARS: usize = 2000;
+
+    let query: QuizQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse quiz query: {:?}", err);
+            return bad_request("Invalid quiz query");
+        }
+    };
+
+    let conn = Connection::open_default()?;
+    let result = match &query.tag {
+        Some(tag) => {
+            let pattern = tag_pattern(tag)?;
+            let query_params = [sqlite::ValueParam::Text(&pattern)];
+            conn.execute(
+                "SELECT * FROM live_paragraphs WHERE tags LIKE ? ESCAPE '\\' ORDER BY RANDOM() LIMIT 1",
+                &query_params,
+            )?
+        }
+        None => conn.execute(
+            "SELECT * FROM live_paragraphs ORDER BY RANDOM() LIMIT 1",
+            &[],
+        )?,
+    };
+    let paragraph = match result.rows().next().map(Paragraph::try_from).transpose()? {
+        Some(paragraph) => paragraph,
+        None => return not_found(),
+    };
+
+    let text: String = paragraph.text.chars().take(MAX_TEXT_CHARS).collect();
+    let inferencing_result = infer(Llama2Chat, &PROMPT.replace("{TEXT}", &text))?;
+
+    json_response(
+        http::StatusCode::OK,
+        &Quiz {
+            question: inferencing_result.text.trim().to_owned(),
+            source_summary: summarize_text(&paragraph.text, SUMMARY_SENTENCES)?.summary,
+            source_reference: paragraph.reference,
+        },
+    )
+}
+
+/// Asks the LLM for one question and answer about each of `count` random
+/// paragraphs, storing them for `GET /embeddings/faq`.
+fn generate_faqs(req: Request, _params: Params) -> Result<Response> {
+    const PROMPT: &str = r#"<s>[INST]<<SYS>>You write FAQs for documentation. Reply in exactly two lines, the question starting with "Q:" and its answer starting with "A:".<</SYS>>Generate one FAQ question and answer from this text: {TEXT} [/INST]"#;
+    // Keep long pages from overflowing the model context window.
+    const MAX_TEXT_CHARS: usize = 2000;
+
+    let query: FaqQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse generate-faq query: {:?}", err);
+            return bad_request("Invalid generate-faq query");
+        }
+    };
+    if !(1..=20).contains(&query.count) {
+        return bad_request("count must be between 1 and 20");
+    }
+
+    let conn = Connection::open_default()?;
+    let count = query.count as i64;
+    let result = match &query.tag {
+        Some(tag) => {
+            let pattern = tag_pattern(tag)?;
+            let query_params = [
+                sqlite::ValueParam::Text(&pattern),
+                sqlite::ValueParam::Integer(count),
+            ];
+            conn.execute(
+                "SELECT * FROM live_paragraphs WHERE tags LIKE ? ESCAPE '\\' ORDER BY RANDOM() LIMIT ?",
+                &query_params,
+            )?
+        }
+        None => conn.execute(
+            "SELECT * FROM live_paragraphs ORDER BY RANDOM() LIMIT ?",
+            &[sqlite::ValueParam::Integer(count)],
+        )?,
+    };
+    let paragraphs = result
+        .rows()
+        .map(Paragraph::try_from)
+        .collect::<Result<Vec<Paragraph>>>()?;
+    if paragraphs.is_empty() {
+        return not_found();
+    }
+
+    let prompt = prompt_template("faq", PROMPT);
+    let mut faqs = vec![];
+    for paragraph in paragraphs {
+        let text: String = paragraph.text.chars().take(MAX_TEXT_CHARS).collect();
+        let reply = match infer(Llama2Chat, &prompt.replace("{TEXT}", &text)) {
+            Ok(inferencing_result) => inferencing_result.text,
+            Err(err) => {
+                error!(
+                    "Failed to generate a FAQ for {}: {:?}",
+                    paragraph.reference, err
+                );
+                continue;
+            }
+        };
+        let Some((question, answer)) = parse_faq(&reply) else {
+            error!("Unexpected FAQ for {}: {:?}", paragraph.reference, reply);
+            continue;
+        };
+        let query_params = [
+            sqlite::ValueParam::Text(question),
+            sqlite::ValueParam::Text(answer),
+            sqlite::ValueParam::Text(&paragraph.reference),
+        ];
+        conn.execute(
+            "INSERT INTO faqs (question, answer, source, created_at) VALUES (?, ?, ?, datetime('now'))",
+            &query_params,
+        )?;
+        faqs.push(Faq {
+            question: question.to_owned(),
+            answer: answer.to_owned(),
+            source: paragraph.reference,
+        });
+    }
+    info!("Generated {} FAQs", faqs.len());
+
+    json_response(http::StatusCode::CREATED, &faqs)
+}
+
+/// Splits a "Q: ... A: ..." reply into its question and answer.
+fn parse_faq(reply: &str) -> Option<(&str, &str)> {
+    let (question, answer) = reply.trim().split_once("\nA:")?;
+    let question = question.trim();
+    let question = question.strip_prefix("Q:").unwrap_or(question).trim();
+    let answer = answer.trim();
+    if question.is_empty() || answer.is_empty() {
+        return None;
+    }
+    Some((question, answer))
+}
+
+/// Lists the FAQs generated by `POST /embeddings/generate-faq`, newest first.
+fn get_faqs(req: Request, _params: Params) -> Result<Response> {
+    let query: ListQuery = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse faq query: {:?}", err);
+            return bad_request("Invalid faq query");
+        }
+    };
+
+    let query_params = [
+        sqlite::ValueParam::Integer(query.limit.unwrap_or(50)),
+        sqlite::ValueParam::Integer(query.offset.unwrap_or(0)),
+    ];
+    let faqs: Vec<Faq> = Connection::open_default()?
+        .execute(
+            "SELECT question, answer, source FROM faqs ORDER BY created_at DESC LIMIT ? OFFSET ?",
+            &query_params,
+        )?
+        .rows()
+        .filter_map(|row| {
+            Some(Faq {
+                question: row.get::<&str>("question")?.to_owned(),
+                answer: row.get::<&str>("answer")?.to_owned(),
+                source: row.get::<&str>("source")?.to_owned(),
+            })
+        })
+        .collect();
+
+    json_response(http::StatusCode::OK, &faqs)
+}
+
+/// Runs the question against two retrieval strategies and stores which
+/// references each returned, so that a reviewer can later pick the better one.
+fn run_ab_test(req: Request, _params: Params) -> Result<Response> {
+    const RESULTS_PER_STRATEGY: usize = 10;
+
+    let query: AbTestQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse A/B test query: {:?}", err);
+            return bad_request("Invalid A/B test query");
+        }
+    };
+
+    let embedded_question = embed_sentence(&query.question)?;
+    let records = get_compare_set(&CompareFilter::default())?;
+    let rank = |strategy: &SearchStrategy| {
+        let mut results: Vec<SimilarityResult> = records
+            .iter()
+            .map(|r| {
+                SimilarityResult::new(
+                    r.to_paragraph(),
+                    strategy.metric.similarity(&r.embedding, &embedded_question),
+                )
+            })
+            .collect();
+        results.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+        results.truncate(RESULTS_PER_STRATEGY);
+        results
+    };
+    let strategy_a = rank(&query.strategy_a);
+    let strategy_b = rank(&query.strategy_b);
+
+    let references = |results: &[SimilarityResult]| {
+        serde_json::to_string(
+            &results
+                .iter()
+                .map(|r| r.paragraph.reference.as_str())
+                .collect::<Vec<&str>>(),
+        )
+    };
+    let strategy_a_refs = references(&strategy_a)?;
+    let strategy_b_refs = references(&strategy_b)?;
+    let query_params = [
+        sqlite::ValueParam::Text(&query.question),
+        sqlite::ValueParam::Text(&strategy_a_refs),
+        sqlite::ValueParam::Text(&strategy_b_refs),
+    ];
+    let id = Connection::open_default()?
+        .execute(
+            "INSERT INTO ab_tests (id, question, strategy_a_refs, strategy_b_refs, created_at) VALUES (lower(hex(randomblob(16))), ?, ?, ?, datetime('now')) RETURNING id",
+            &query_params,
+        )?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<&str>("id").map(str::to_owned))
+        .context("A/B test was not stored")?;
+    info!("Stored A/B test {}", id);
+
+    json_response(
+        http::StatusCode::CREATED,
+        &AbTestResultSet {
+            id,
+            question: query.question,
+            strategy_a,
+            strategy_b,
+        },
+    )
+}
+
+/// Returns a stored A/B test along with the strategy a reviewer preferred.
+fn get_ab_test_result(_req: Request, params: Params) -> Result<Response> {
+    let id = match params.get("id") {
+        Some(id) => id,
+        None => return not_found(),
+    };
+
+    match load_ab_test(&Connection::open_default()?, id)? {
+        Some(ab_test) => json_response(http::StatusCode::OK, &ab_test),
+        None => not_found(),
+    }
+}
+
+/// Records which strategy of a stored A/B test the reviewer preferred.
+fn record_ab_test_preference(req: Request, params: Params) -> Result<Response> {
+    let id = match params.get("id") {
+        Some(id) => id,
+        None => return not_found(),
+    };
+    let query: AbTestPreferenceQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse A/B test preference: {:?}", err);
+            return bad_request("preferred must be one of: a, b");
+        }
+    };
+
+    let conn = Connection::open_default()?;
+    let query_params = [
+        sqlite::ValueParam::Text(query.preferred.as_str()),
+        sqlite::ValueParam::Text(id),
+    ];
+    conn.execute(
+        "UPDATE ab_tests SET preferred = ? WHERE id = ?",
+        &query_params,
+    )?;
+
+    match load_ab_test(&conn, id)? {
+        Some(ab_test) => json_response(http::StatusCode::OK, &ab_test),
+        None => not_found(),
+    }
+}
+
+fn load_ab_test(conn: &Connection, id: &str) -> Result<Option<AbTest>> {
+    let query_params = [sqlite::ValueParam::Text(id)];
+    let result = conn.execute("SELECT * FROM ab_tests WHERE id = ?", &query_params)?;
+    let ab_test = result.rows().next().map(|row| AbTest {
+        id: id.to_owned(),
+        question: row.get::<&str>("question").unwrap_or_default().to_owned(),
+        strategy_a_refs: parse_string_list(row.get::<&str>("strategy_a_refs")),
+        strategy_b_refs: parse_string_list(row.get::<&str>("strategy_b_refs")),
+        preferred: row.get::<&str>("preferred").map(str::to_owned),
+        created_at: row.get::<&str>("created_at").unwrap_or_default().to_owned(),
+    });
+    Ok(ab_test)
+}
+
+fn translate_and_search(req: Request, _params: Params) -> Result<Response> {
+    let query: TranslateQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse translate query: {:?}", err);
+            return bad_request("Invalid translate query");
+        }
+    };
+
+    let translated_query = translate_sentence(&query.sentence, &query.target_language)?;
+    info!("Translated {:?} to {:?}", query.sentence, translated_query);
+    let result_set = get_similar_paragraphs(
+        &translated_query,
+        &CompareFilter::default(),
+        &RankingOptions::default(),
+    )?;
+    record_retrievals(&result_set.results);
+    record_search(&translated_query);
+
+    json_response(
+        http::StatusCode::OK,
+        &TranslatedResultSet {
+            translated_query,
+            results: result_set.results,
+        },
+    )
+}
+
+/// Searches for paragraphs like an LLM-written answer to the question, see
+/// `hypothetical_document`.
+fn search_hypothetical_doc(req: Request, _params: Params) -> Result<Response> {
+    let query: HypotheticalDocQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse hypothetical-doc query: {:?}", err);
+            return bad_request("Invalid hypothetical-doc query");
+        }
+    };
+
+    let result_set = get_similar_paragraphs(
+        &query.question,
+        &CompareFilter::default(),
+        &RankingOptions {
+            hyde: true,
+            ..Default::default()
+        },
+    )?;
+    record_retrievals(&result_set.results);
+    record_search(&query.question);
+
+    json_response(http::StatusCode::OK, &result_set)
+}
+
+/// Searches for paragraphs like the question or any of its rephrasings, see
+/// `expand_query`.
+fn search_expanded_query(req: Request, _params: Params) -> Result<Response> {
+    let query: QueryExpansionQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse query-expansion query: {:?}", err);
+            return bad_request("Invalid query-expansion query");
+        }
+    };
+    if !(1..=10).contains(&query.expansions) {
+        return bad_request("expansions must be between 1 and 10");
+    }
+
+    let result_set = get_similar_paragraphs(
+        &query.question,
+        &CompareFilter::default(),
+        &RankingOptions {
+            expansions: Some(query.expansions),
+            ..Default::default()
+        },
+    )?;
+    record_retrievals(&result_set.results);
+    record_search(&query.question);
+
+    json_response(http::StatusCode::OK, &result_set)
+}
+
+/// Summarizes the text without embedding or storing it.
+fn summarize(req: Request, _params: Params) -> Result<Response> {
+    // Inference is far more expensive than a search, so summaries are
+    // throttled on their own.
+    const RATE_LIMIT_SECONDS: i64 = 5;
+
+    let params: SummarizeParams = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(p) => p,
+        Err(err) => {
+            error!("Failed to parse summarize parameters: {:?}", err);
+            return bad_request("model must be one of: llama2-chat, codellama-instruct");
+        }
+    };
+    let query: SummarizeQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse summarize query: {:?}", err);
+            return bad_request("Invalid summarize query");
+        }
+    };
+    if query.text.trim().chars().count() < MIN_TEXT_LENGTH {
+        return bad_request(&format!(
+            "text must be at least {} characters long",
+            MIN_TEXT_LENGTH
+        ));
+    }
+
+    if !acquire_rate_limit(
+        &Connection::open_default()?,
+        "summarize",
+        RATE_LIMIT_SECONDS,
+    )? {
+        return Ok(http::Response::builder()
+            .status(http::StatusCode::TOO_MANY_REQUESTS)
+            .header("Retry-After", RATE_LIMIT_SECONDS.to_string())
+            .body(Some("Too many summarize requests".into()))?);
+    }
+
+    let summary = summarize_text_with(
+        params.model.inferencing_model(),
+        &query.text,
+        SUMMARY_SENTENCES,
+    )?;
+    json_response(http::StatusCode::OK, &summary)
+}
+
+/// Records a call to `endpoint` unless the previous one was less than
+/// `interval_seconds` ago. Returns whether the call may proceed.
+fn acquire_rate_limit(conn: &Connection, endpoint: &str, interval_seconds: i64) -> Result<bool> {
+    let query_params = [
+        sqlite::ValueParam::Text(endpoint),
+        sqlite::ValueParam::Integer(interval_seconds),
+    ];
+    conn.execute(
+        "INSERT INTO rate_limits (endpoint, last_called_at) VALUES (?, CAST(strftime('%s', 'now') AS INTEGER))
+        ON CONFLICT(endpoint) DO UPDATE SET last_called_at = excluded.last_called_at
+        WHERE excluded.last_called_at - rate_limits.last_called_at >= ?;",
+        &query_params,
+    )?;
+    let changed = conn
+        .execute("SELECT changes() AS changed", &[])?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("changed"))
+        .unwrap_or_default();
+    Ok(changed > 0)
+}
+
+fn aggregate_similar_paragraphs(req: Request, _params: Params) -> Result<Response> {
+    let params: AggregateParams = match serde_qs::from_str(req.uri().query().unwrap_or_default()) {
+        Ok(p) => p,
+        Err(err) => {
+            error!("Failed to parse aggregate parameters: {:?}", err);
+            return bad_request("aggregate must be one of: domain, tag");
+        }
+    };
+    let query: AggregateQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse aggregate query: {:?}", err);
+            return bad_request("Invalid aggregate query");
+        }
+    };
+
+    let mut results = get_similar_paragraphs(
+        &query.sentence,
+        &CompareFilter::default(),
+        &RankingOptions::default(),
+    )?
+    .results;
+    results.truncate(query.top_k);
+    let cluster_keys = get_cluster_keys(&params.aggregate)?;
+
+    // Results are sorted by similarity, so the first member of each cluster
+    // is its best match and becomes the representative.
+    let mut clusters: Vec<ClusterResult> = vec![];
+    let mut cluster_index: HashMap<String, usize> = HashMap::new();
+    for result in results {
+        let keys = match cluster_keys.get(&result.paragraph.reference) {
+            Some(keys) => keys,
+            None => continue,
+        };
+        for key in keys {
+            match cluster_index.get(key) {
+                Some(&i) => {
+                    clusters[i].score += result.similarity;
+                    clusters[i].member_count += 1;
+                }
+                None => {
+                    cluster_index.insert(key.clone(), clusters.len());
+                    clusters.push(ClusterResult {
+                        cluster: key.clone(),
+                        representative: result.paragraph.clone(),
+                        score: result.similarity,
+                        member_count: 1,
+                    });
+                }
+            }
+        }
+    }
+    for cluster in clusters.iter_mut() {
+        cluster.score /= cluster.member_count as f32;
+    }
+    clusters.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    json_response(http::StatusCode::OK, &clusters)
+}
+
+/// Maps each reference to the clusters it belongs to. Untagged paragraphs
+/// belong to no tag cluster.
+fn get_cluster_keys(aggregate: &AggregateBy) -> Result<HashMap<String, Vec<String>>> {
+    let result = Connection::open_default()?.execute(
+        "SELECT reference, source_domain, tags FROM live_paragraphs",
+        &[],
+    )?;
+
+    let mut cluster_keys = HashMap::new();
+    for row in result.rows() {
+        let reference = row
+            .get::<&str>("reference")
+            .context("reference column is empty")?;
+        let keys = match aggregate {
+            AggregateBy::Domain => vec![row
+                .get::<&str>("source_domain")
+                .map(str::to_owned)
+                .or_else(|| domain_of(reference))
+                .unwrap_or_else(|| reference.to_owned())],
+            AggregateBy::Tag => parse_string_list(row.get::<&str>("tags")),
+        };
+        cluster_keys.insert(reference.to_owned(), keys);
+    }
+    Ok(cluster_keys)
+}
+
+/// Ranks the paragraphs matching `filter` by the BM25 score of their text for
+/// the words of the sentence, using the FTS5 index. The score, which FTS5
+/// reports negated as `rank`, is returned as `similarity`.
+fn search_bm25(sentence: &str, filter: &CompareFilter) -> Result<SimilarityResultSet> {
+    // Any word of the sentence matches, so common words would otherwise return
+    // most of the corpus.
+    const BM25_LIMIT: i64 = 50;
+
+    // Quoted, so words aren't read as FTS5 operators, and any of them matches.
+    let match_query = sentence
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"", word))
+        .collect::<Vec<_>>()
+        .join(" OR ");
+    let mut results = vec![];
+    if !match_query.is_empty() {
+        // The matches are filtered in an outer query, where the paragraph
+        // columns, such as `text`, aren't ambiguous with the FTS5 table's.
+        let mut sql_query = String::from(
+            "SELECT * FROM (SELECT live_paragraphs.*, paragraphs_fts.rank AS bm25_rank FROM paragraphs_fts JOIN live_paragraphs ON live_paragraphs.reference = paragraphs_fts.reference WHERE paragraphs_fts MATCH ?)",
+        );
+        let keyword = filter.keyword.as_deref().map(keyword_condition);
+        let (conditions, filter_params) = filter_conditions(filter, &keyword);
+        if !conditions.is_empty() {
+            sql_query.push_str(" WHERE ");
+            sql_query.push_str(&conditions.join(" AND "));
+        }
+        sql_query.push_str(" ORDER BY bm25_rank LIMIT ?");
+        let mut query_params = vec![sqlite::ValueParam::Text(&match_query)];
+        query_params.extend(filter_params);
+        query_params.push(sqlite::ValueParam::Integer(BM25_LIMIT));
+        let rows = Connection::open_default()?.execute(&sql_query, &query_params)?;
+        for row in rows.rows() {
+            let similarity = -row.get::<f64>("bm25_rank").unwrap_or_default() as f32;
+            results.push(SimilarityResult::new(Paragraph::try_from(row)?, similarity));
+        }
+    }
+
+    Ok(SimilarityResultSet {
+        sentence: Some(sentence.to_string()),
+        model: Some("bm25"),
+        hypothetical_doc: None,
+        expanded_queries: None,
+        results,
+    })
+}
+
+/// Ranks the paragraphs matching `filter` by similarity to `sentence`, then
+/// adjusts the ranking as requested by `ranking`.
+fn get_similar_paragraphs(
+    sentence: &str,
+    filter: &CompareFilter,
+    ranking: &RankingOptions,
+) -> Result<SimilarityResultSet> {
+    // Generated texts are rarely searched twice, so they bypass the query
+    // embedding cache.
+    let (embedded_sentence, hypothetical_doc, expanded_queries) = if ranking.hyde {
+        let document = hypothetical_document(sentence)?;
+        let embedding = generate_normalized_embeddings(&[document.as_str()])?
+            .embeddings
+            .into_iter()
+            .next()
+            .context("LLM returned no embedding")?;
+        (embedding, Some(document), None)
+    } else if let Some(expansions) = ranking.expansions {
+        let queries = expand_query(sentence, expansions)?;
+        let texts: Vec<&str> = std::iter::once(sentence)
+            .chain(queries.iter().map(String::as_str))
+            .collect();
+        let embeddings = generate_normalized_embeddings(&texts)?.embeddings;
+        (merge_embeddings(&embeddings), None, Some(queries))
+    } else {
+        (embed_sentence(sentence)?, None, None)
+    };
+    let mut results = rank_paragraphs(filter, &embedded_sentence)?;
+    if let Some(weight) = ranking.pagerank_weight {
+        blend_page_rank(&mut results, weight)?;
+    }
+    if ranking.date_boost {
+        boost_recent(&mut results, variable("date_decay_rate", 0.01)?)?;
+    }
+    if let Some(keyword) = &filter.keyword {
+        blend_keyword_density(&mut results, keyword, ranking.keyword_weight.unwrap_or(0.7));
+    }
+
+    results.sort_by(|a, b| {
+        let order = b.similarity.total_cmp(&a.similarity);
+        match ranking.sort {
+            // Like the max_depth filter, unknown depths count as top-level pages.
+            Some(ResultSort::DepthAsc) => order.then_with(|| {
+                let depth = |r: &SimilarityResult| r.paragraph.crawl_depth.unwrap_or(0);
+                depth(a).cmp(&depth(b))
+            }),
+            None => order,
+        }
+    });
+    if let Some(lambda) = ranking.mmr_lambda.filter(|lambda| *lambda < 1.0) {
+        diversity_rerank(&mut results, lambda)?;
+    }
+    if ranking.snippets {
+        attach_snippets(&mut results, &embedded_sentence);
+    }
+
+    let similarity_results = SimilarityResultSet {
+        sentence: Some(sentence.to_string()),
+        model: Some(EMBEDDING_MODEL),
+        hypothetical_doc,
+        expanded_queries,
+        results,
+    };
+
+    Ok(similarity_results)
+}
+
+/// Searches like `GET /embeddings` with the recency boost always enabled.
+fn rerank_by_date(req: Request, _params: Params) -> Result<Response> {
+    let query: Query = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse rerank-by-date query: {:?}", err);
+            return bad_request("Invalid rerank-by-date query");
+        }
+    };
+    let ranking = RankingOptions {
+        date_boost: true,
+        ..query.ranking()
+    };
+    if let Err(message) = ranking.validate() {
+        return bad_request(message);
+    }
+
+    let result_set = get_similar_paragraphs(&query.sentence, &query.filter(), &ranking)?;
+    record_retrievals(&result_set.results);
+    record_search(&query.sentence);
+
+    json_response(http::StatusCode::OK, &result_set)
+}
+
+/// Searches with an embedding computed by the caller, skipping the embedding
+/// model entirely.
+fn search_by_vector(req: Request, _params: Params) -> Result<Response> {
+    let query: VectorQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse vector query: {:?}", err);
+            return bad_request("Invalid vector query");
+        }
+    };
+    if query.embedding.len() != EMBEDDING_DIM {
+        return bad_request(&format!(
+            "embedding must have {} dimensions, got {}",
+            EMBEDDING_DIM,
+            query.embedding.len()
+        ));
+    }
+    if !is_valid_embedding(&query.embedding) {
+        return bad_request("embedding must be finite and non-zero");
+    }
+
+    let mut results = rank_paragraphs(&CompareFilter::default(), &query.embedding)?;
+    results.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    results.truncate(query.top_k);
+    record_retrievals(&results);
+
+    json_response(
+        http::StatusCode::OK,
+        &SimilarityResultSet {
+            sentence: None,
+            model: None,
+            hypothetical_doc: None,
+            expanded_queries: None,
+            results,
+        },
+    )
+}
+
+/// Finds the paragraphs of one domain closest to the centroid of another's,
+/// i.e. the content of `source_domain` most like `target_domain` overall.
+fn cross_reference_domains(req: Request, _params: Params) -> Result<Response> {
+    let query: CrossReferenceQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse cross-reference query: {:?}", err);
+            return bad_request("Invalid cross-reference query");
+        }
+    };
+
+    let target = load_compare_set(&CompareFilter {
+        domain: Some(query.target_domain.to_lowercase()),
+        ..Default::default()
+    })?;
+    if target.is_empty() {
+        return not_found();
+    }
+    let target_centroid = centroid(target.iter().map(|p| p.embedding.as_slice()));
+
+    let mut results = rank_paragraphs(
+        &CompareFilter {
+            domain: Some(query.source_domain.to_lowercase()),
+            ..Default::default()
+        },
+        &target_centroid,
+    )?;
+    results.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    results.truncate(query.top_k);
+
+    json_response(
+        http::StatusCode::OK,
+        &CrossReferenceResult {
+            target_centroid_dim: target_centroid.len(),
+            results,
+        },
+    )
+}
+
+/// Combines the embeddings of stored paragraphs and finds the paragraph
+/// closest to the result, other than the ones combined.
+fn compute_vector_math(req: Request, _params: Params) -> Result<Response> {
+    let query: VectorMathQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse vector-math query: {:?}", err);
+            return bad_request("Invalid vector-math query");
+        }
+    };
+    match query.operation {
+        VectorOperation::Add | VectorOperation::Subtract if query.references.len() < 2 => {
+            return bad_request("add and subtract take at least 2 references");
+        }
+        VectorOperation::Scale if query.references.len() != 1 => {
+            return bad_request("scale takes exactly 1 reference");
+        }
+        _ => {}
+    }
+
+    let conn = Connection::open_default()?;
+    let mut embeddings = Vec::with_capacity(query.references.len());
+    for reference in &query.references {
+        match find_paragraph_record(&conn, reference)? {
+            Some(record) => embeddings.push(record.embedding),
+            None => return not_found(),
+        }
+    }
+    let (first, rest) = embeddings
+        .split_first()
+        .context("No embeddings to combine")?;
+    let embedding = match query.operation {
+        VectorOperation::Add => rest
+            .iter()
+            .fold(first.clone(), |sum, e| vector_math::add(&sum, e)),
+        VectorOperation::Subtract => rest.iter().fold(first.clone(), |difference, e| {
+            vector_math::subtract(&difference, e)
+        }),
+        VectorOperation::Scale => vector_math::scale(first, query.factor),
+    };
+
+    let mut results = rank_paragraphs(&CompareFilter::default(), &l2_normalized(&embedding))?;
+    results.retain(|r| !query.references.contains(&r.paragraph.reference));
+    results.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+
+    json_response(
+        http::StatusCode::OK,
+        &VectorMathResult {
+            embedding,
+            nearest: results.into_iter().next(),
+        },
+    )
+}
+
+/// Runs the steps of a search pipeline over the whole corpus, each one
+/// filtering or reordering the candidates left by the one before.
+fn run_search_pipeline(req: Request, _params: Params) -> Result<Response> {
+    const MAX_STEPS: usize = 10;
+
+    let query: PipelineQuery = match serde_json::from_slice(body_bytes(&req)) {
+        Ok(q) => q,
+        Err(err) => {
+            error!("Failed to parse pipeline query: {:?}", err);
+            return bad_request("Invalid pipeline query");
+        }
+    };
+    if query.steps.is_empty() || query.steps.len() > MAX_STEPS {
+        return bad_request("A pipeline takes between 1 and 10 steps");
+    }
+    if let Some(message) = query.steps.iter().find_map(PipelineStep::validate) {
+        return bad_request(message);
+    }
+
+    let mut candidates: Vec<ScoredRecord> = get_compare_set(&CompareFilter::default())?
+        .into_iter()
+        .map(|record| ScoredRecord { record, score: 0.0 })
+        .collect();
+    for step in &query.steps {
+        candidates = step.as_search_step().apply(candidates)?;
+    }
+
+    let results: Vec<SimilarityResult> = candidates
+        .into_iter()
+        .map(|c| SimilarityResult::new(c.record.into_paragraph(), c.score))
+        .collect();
+    record_retrievals(&results);
+
+    json_response(
+        http::StatusCode::OK,
+        &SimilarityResultSet {
+            sentence: None,
+            model: Some(EMBEDDING_MODEL),
+            hypothetical_doc: None,
+            expanded_queries: None,
+            results,
+        },
+    )
+}
+
+/// A paragraph with the score the pipeline has given it so far.
+struct ScoredRecord {
+    record: ParagraphRecord,
+    score: f32,
+}
+
+/// One step of `POST /embeddings/search/pipeline`.
+trait SearchStep {
+    fn apply(&self, candidates: Vec<ScoredRecord>) -> Result<Vec<ScoredRecord>>;
+}
+
+impl SearchStep for KeywordFilterStep {
+    /// Keeps the candidates matching the keyword like a `keyword` filter,
+    /// ignoring case.
+    fn apply(&self, mut candidates: Vec<ScoredRecord>) -> Result<Vec<ScoredRecord>> {
+        let keyword = self.keyword.to_lowercase();
+        match keyword.strip_prefix("extracted:") {
+            Some(extracted) => candidates.retain(|c| {
+                c.record
+                    .keywords_extracted
+                    .iter()
+                    .any(|k| k.to_lowercase() == extracted)
+            }),
+            None => candidates.retain(|c| c.record.text.to_lowercase().contains(&keyword)),
+        }
+        Ok(candidates)
+    }
+}
+
+impl SearchStep for SemanticSearchStep {
+    /// Scores the candidates by their similarity to the sentence and keeps the
+    /// `top_k` best.
+    fn apply(&self, mut candidates: Vec<ScoredRecord>) -> Result<Vec<ScoredRecord>> {
+        let embedded_sentence = embed_sentence(&self.sentence)?;
+        for candidate in candidates.iter_mut() {
+            candidate.score = cosine_similarity(&candidate.record.embedding, &embedded_sentence);
+        }
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+        candidates.truncate(self.top_k);
+        Ok(candidates)
+    }
+}
+
+impl SearchStep for MmrRerankStep {
+    /// Reorders the best candidates by Maximal Marginal Relevance, like
+    /// `diversity_rerank`, with the MMR score as their new score.
+    fn apply(&self, candidates: Vec<ScoredRecord>) -> Result<Vec<ScoredRecord>> {
+        let reranked = mmr_rerank(
+            candidates,
+            self.lambda,
+            |candidate| candidate.score,
+            |candidate| &candidate.record.embedding,
+        );
+        Ok(reranked
+            .into_iter()
+            .map(|(mut candidate, mmr_score)| {
+                if let Some(mmr_score) = mmr_score {
+                    candidate.score = mmr_score;
+                }
+                candidate
+            })
+            .collect())
+    }
+}
+
+impl SearchStep for DateBoostStep {
+    /// Scales the scores down with age like `use_date_boost` and reorders the
+    /// candidates.
+    fn apply(&self, mut candidates: Vec<ScoredRecord>) -> Result<Vec<ScoredRecord>> {
+        let decay_rate = match self.decay_rate {
+            Some(decay_rate) => decay_rate,
+            None => variable("date_decay_rate", 0.01)?,
+        };
+        let age_days = paragraph_ages()?;
+        for candidate in candidates.iter_mut() {
+            candidate.score *= recency_decay(age_days.get(&candidate.record.reference), decay_rate);
+        }
+        candidates.sort_by(|a, b| b.score.total_cmp(&a.score));
+        Ok(candidates)
+    }
+}
+
+impl SearchStep for TopKStep {
+    fn apply(&self, mut candidates: Vec<ScoredRecord>) -> Result<Vec<ScoredRecord>> {
+        candidates.truncate(self.k);
+        Ok(candidates)
+    }
+}
+
+fn blend_page_rank(results: &mut [SimilarityResult], weight: f32) -> Result<()> {
+    let conn = Connection::open_default()?;
+    let ranks = conn.execute("SELECT reference, page_rank FROM live_paragraphs", &[])?;
+    let page_ranks: HashMap<&str, f64> = ranks
+        .rows()
+        .filter_map(|row| Some((row.get::<&str>("reference")?, row.get::<f64>("page_rank")?)))
+        .collect();
+    let max_rank = page_ranks.values().copied().fold(0.0, f64::max);
+    if max_rank <= 0.0 {
+        return Ok(());
+    }
+
+    for result in results.iter_mut() {
+        let rank = page_ranks
+            .get(result.paragraph.reference.as_str())
+            .map_or(0.0, |rank| (rank / max_rank) as f32);
+        result.similarity = (1.0 - weight) * result.similarity + weight * rank;
+    }
+    Ok(())
+}
+
+/// Blends the density of the keyword in each text into its similarity as
+/// `weight * similarity + (1 - weight) * density`.
+fn blend_keyword_density(results: &mut [SimilarityResult], keyword: &str, weight: f32) {
+    let keyword = keyword
+        .strip_prefix("extracted:")
+        .unwrap_or(keyword)
+        .to_lowercase();
+    if keyword.is_empty() {
+        return;
+    }
+
+    for result in results.iter_mut() {
+        let text = result.paragraph.text.to_lowercase();
+        let words = result
+            .paragraph
+            .word_count
+            .unwrap_or_else(|| word_count(&text));
+        let density = match words {
+            0 => 0.0,
+            words => text.matches(&keyword).count() as f32 / words as f32,
+        };
+        result.similarity = weight * result.similarity + (1.0 - weight) * density;
+        result.keyword_density = Some(density);
+    }
+}
+
+/// Decays the similarity of older paragraphs by `exp(-decay_rate * days)`,
+/// where `days` have passed since the page was loaded. Paragraphs without a
+/// load time keep their similarity.
+fn boost_recent(results: &mut [SimilarityResult], decay_rate: f64) -> Result<()> {
+    let age_days = paragraph_ages()?;
+    for result in results.iter_mut() {
+        let decay = recency_decay(age_days.get(&result.paragraph.reference), decay_rate);
+        result.raw_similarity = Some(result.similarity);
+        result.similarity *= decay;
+        result.boosted_similarity = Some(result.similarity);
+    }
+    Ok(())
+}
+
+/// Days since each paragraph with a known `loaded_at` was loaded, by reference.
+fn paragraph_ages() -> Result<HashMap<String, f64>> {
+    let ages = Connection::open_default()?.execute(
+        "SELECT reference, julianday('now') - julianday(loaded_at) AS age_days FROM live_paragraphs WHERE loaded_at IS NOT NULL",
+        &[],
+    )?;
+    Ok(ages
+        .rows()
+        .filter_map(|row| {
+            Some((
+                row.get::<&str>("reference")?.to_owned(),
+                row.get::<f64>("age_days")?,
+            ))
+        })
+        .collect())
+}
+
+/// Factor by which the score of a paragraph of the given age is multiplied.
+/// Paragraphs of unknown age aren't penalized.
+fn recency_decay(age_days: Option<&f64>, decay_rate: f64) -> f32 {
+    age_days.map_or(1.0, |days| (-decay_rate * days.max(0.0)).exp() as f32)
+}
+
+/// How many of the best results Maximal Marginal Relevance reorders.
+const MMR_CANDIDATES: usize = 50;
+
+/// Reorders the best results by Maximal Marginal Relevance, see `mmr_rerank`,
+/// setting their `mmr_score`.
+fn diversity_rerank(results: &mut Vec<SimilarityResult>, lambda: f32) -> Result<()> {
+    let references: Vec<&str> = results
+        .iter()
+        .take(MMR_CANDIDATES)
+        .map(|r| r.paragraph.reference.as_str())
+        .collect();
+    let embeddings = get_embeddings(&references)?;
+
+    let candidates: Vec<(SimilarityResult, Vec<f32>)> = results
+        .drain(..)
+        .map(|r| {
+            let embedding = embeddings
+                .get(&r.paragraph.reference)
+                .cloned()
+                .unwrap_or_default();
+            (r, embedding)
+        })
+        .collect();
+    let reranked = mmr_rerank(
+        candidates,
+        lambda,
+        |(result, _)| result.similarity,
+        |(_, embedding)| embedding,
+    );
+    for ((mut result, _), score) in reranked {
+        result.mmr_score = score;
+        results.push(result);
+    }
+    Ok(())
+}
+
+/// Reorders the first `MMR_CANDIDATES` items by Maximal Marginal Relevance:
+/// each pick maximizes `lambda * score - (1 - lambda) * redundancy`, where
+/// redundancy is the highest similarity to an item picked before it. Returns
+/// the items with their MMR score; those beyond the candidates considered keep
+/// their order after the reranked ones, without a score.
+fn mmr_rerank<T>(
+    mut items: Vec<T>,
+    lambda: f32,
+    score: impl Fn(&T) -> f32,
+    embedding: impl Fn(&T) -> &[f32],
+) -> Vec<(T, Option<f32>)> {
+    let rest = items.split_off(items.len().min(MMR_CANDIDATES));
+    let mut reranked: Vec<(T, Option<f32>)> = Vec::with_capacity(items.len() + rest.len());
+    while !items.is_empty() {
+        let (best, mmr_score) = items
+            .iter()
+            .map(|candidate| {
+                let redundancy = reranked
+                    .iter()
+                    .map(|(picked, _)| cosine_similarity(embedding(candidate), embedding(picked)))
+                    .fold(0.0, f32::max);
+                lambda * score(candidate) - (1.0 - lambda) * redundancy
+            })
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .expect("Candidates should not be empty");
+        reranked.push((items.remove(best), Some(mmr_score)));
+    }
+    reranked.extend(rest.into_iter().map(|item| (item, None)));
+    reranked
+}
+
+/// Loads the embeddings of the given references, keyed by reference.
+fn get_embeddings(references: &[&str]) -> Result<HashMap<String, Vec<f32>>> {
+    if references.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let placeholders = vec!["?"; references.len()].join(", ");
+    let query_params: Vec<sqlite::ValueParam> = references
+        .iter()
+        .map(|reference| sqlite::ValueParam::Text(reference))
+        .collect();
+    let result = Connection::open_default()?.execute(
+        &format!(
+            "SELECT * FROM live_paragraphs WHERE reference IN ({})",
+            placeholders
+        ),
+        &query_params,
+    )?;
+    result
+        .rows()
+        .map(|row| {
+            let record = ParagraphRecord::try_from(row)?;
+            Ok((record.reference, record.embedding))
+        })
+        .collect()
+}
+
+#[cfg(not(feature = "hnsw"))]
+fn rank_paragraphs(
+    filter: &CompareFilter,
+    query_embedding: &[f32],
+) -> Result<Vec<SimilarityResult>> {
+    Ok(linear_rank(get_compare_set(filter)?, query_embedding))
+}
+
+/// Serves unfiltered searches from the HNSW index, returning only the closest
+/// paragraphs. Filtered searches fall back to a linear scan.
+#[cfg(feature = "hnsw")]
+fn rank_paragraphs(
+    filter: &CompareFilter,
+    query_embedding: &[f32],
+) -> Result<Vec<SimilarityResult>> {
+    const APPROXIMATE_RESULTS: usize = 50;
+    const EF_SEARCH: usize = 100;
+
+    if !filter.is_empty() {
+        return Ok(linear_rank(get_compare_set(filter)?, query_embedding));
+    }
+
+    let mut index = SEARCH_INDEX
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Search index lock is poisoned"))?;
+    // Read before loading the records, so a write made meanwhile still counts
+    // as newer than the index.
+    let version = corpus_version(&Connection::open_default()?)?;
+    if index.as_ref().map(|index| index.corpus_version) != Some(version) {
+        let records = get_compare_set(filter)?;
+        let graph = hnsw::Hnsw::build(records.iter().map(|r| r.embedding.clone()).collect());
+        info!("Built HNSW index over {} paragraphs", records.len());
+        *index = Some(SearchIndex {
+            records,
+            graph,
+            corpus_version: version,
+        });
+    }
+    let index = index.as_ref().expect("Search index should have been built");
+
+    Ok(index
+        .graph
+        .search(query_embedding, APPROXIMATE_RESULTS, EF_SEARCH)
+        .into_iter()
+        .map(|(node, similarity)| {
+            SimilarityResult::new(index.records[node].to_paragraph(), similarity)
+        })
+        .collect())
+}
+
+fn linear_rank(paragraphs: Vec<ParagraphRecord>, query_embedding: &[f32]) -> Vec<SimilarityResult> {
+    paragraphs
+        .into_iter()
+        .map(|p| {
+            let similarity = cosine_similarity(p.embedding.as_ref(), query_embedding);
+            SimilarityResult::new(p.into_paragraph(), similarity)
+        })
+        .collect()
+}
+
+/// Drops the warm paragraphs and bumps the `corpus_version` after paragraphs
+/// change, which marks the approximate search indexes of all instances as
+/// stale.
+fn invalidate_search_index() {
+    match WARM_RECORDS.lock() {
+        Ok(mut records) => *records = None,
+        Err(_) => error!("Warm paragraphs lock is poisoned"),
+    }
+    let bumped = Connection::open_default().and_then(|conn| {
+        conn.execute(
+            "UPDATE corpus_version SET version = version + 1 WHERE id = 1",
+            &[],
+        )
+    });
+    if let Err(err) = bumped {
+        error!("Failed to bump the corpus version: {:?}", err);
+    }
+}
+
+/// Counter of the changes made to the paragraphs, for in-memory copies of them
+/// to tell whether they are stale.
+fn corpus_version(conn: &Connection) -> Result<i64> {
+    let version = conn
+        .execute("SELECT version FROM corpus_version WHERE id = 1", &[])?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("version"))
+        .unwrap_or_default();
+    Ok(version)
+}
+
+/// Counts the top results of a search as retrieved, so that paragraphs which
+/// never surface can be found through `GET /embeddings/orphans`.
+fn record_retrievals(results: &[SimilarityResult]) {
+    const RETRIEVED_RESULTS: usize = 10;
+
+    let conn = match Connection::open_default() {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!("Failed to record retrievals: {:?}", err);
+            return;
+        }
+    };
+    for result in results.iter().take(RETRIEVED_RESULTS) {
+        let query_params = [sqlite::ValueParam::Text(&result.paragraph.reference)];
+        if let Err(err) = conn.execute(
+            "UPDATE paragraphs SET retrieval_count = IFNULL(retrieval_count, 0) + 1 WHERE reference = ?",
+            &query_params,
+        ) {
+            error!("Failed to record retrieval: {:?}", err);
+        }
+    }
+}
+
+/// Counts a search for `sentence` in `search_history`, for
+/// `GET /embeddings/search/history`.
+fn record_search(sentence: &str) {
+    let result = Connection::open_default().and_then(|conn| {
+        let query_params = [
+            sqlite::ValueParam::Text(sentence),
+            sqlite::ValueParam::Text(sentence),
+        ];
+        conn.execute(
+            "INSERT OR REPLACE INTO search_history (sentence, count, last_queried) VALUES (?, COALESCE((SELECT count + 1 FROM search_history WHERE sentence = ?), 1), datetime('now'))",
+            &query_params,
+        )
+    });
+    if let Err(err) = result {
+        error!("Failed to record search: {:?}", err);
+    }
+}
+
+/// Sets `snippet` on the top results to their sentence closest to the query.
+/// Sentence embeddings are expensive, so they are only computed for the first
+/// few results and cached in the `sentence_embeddings` column.
+fn attach_snippets(results: &mut [SimilarityResult], query_embedding: &[f32]) {
+    const SNIPPET_RESULTS: usize = 3;
+
+    let conn = match Connection::open_default() {
+        Ok(conn) => conn,
+        Err(err) => {
+            error!("Failed to attach snippets: {:?}", err);
+            return;
+        }
+    };
+    for result in results.iter_mut().take(SNIPPET_RESULTS) {
+        match best_snippet(&conn, &result.paragraph, query_embedding) {
+            Ok(snippet) => result.paragraph.snippet = snippet,
+            Err(err) => error!(
+                "Failed to compute snippet for {}: {:?}",
+                result.paragraph.reference, err
+            ),
+        }
+    }
+}
+
+fn best_snippet(
+    conn: &Connection,
+    paragraph: &Paragraph,
+    query_embedding: &[f32],
+) -> Result<Option<String>> {
+    let snippet = get_sentence_embeddings(conn, paragraph)?
+        .into_iter()
+        .map(|s| (cosine_similarity(&s.embedding, query_embedding), s.sentence))
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, sentence)| sentence);
+    Ok(snippet)
+}
+
+fn get_sentence_embeddings(
+    conn: &Connection,
+    paragraph: &Paragraph,
+) -> Result<Vec<SentenceEmbedding>> {
+    let query_params = [sqlite::ValueParam::Text(&paragraph.reference)];
+    let cached = conn.execute(
+        "SELECT sentence_embeddings FROM live_paragraphs WHERE reference = ?",
+        &query_params,
+    )?;
+    if let Some(row) = cached.rows().next() {
+        if let Some(ValueResult::Blob(b)) = row.get::<&ValueResult>("sentence_embeddings") {
+            return Ok(serde_json::from_slice(b.as_slice())?);
+        }
+    }
+
+    // Crawled texts keep their line breaks as `<br>`, which end a sentence too.
+    let sentences: Vec<&str> = paragraph
+        .text
+        .split("<br>")
+        .flat_map(sentence_splitter::split_by_sentence)
+        .collect();
+    if sentences.is_empty() {
+        return Ok(vec![]);
+    }
+    let embeddings = generate_normalized_embeddings(&sentences)?.embeddings;
+    let sentence_embeddings: Vec<SentenceEmbedding> = sentences
+        .into_iter()
+        .zip(embeddings)
+        .map(|(sentence, embedding)| SentenceEmbedding {
+            sentence: sentence.to_owned(),
+            embedding,
+        })
+        .collect();
+
+    let blob = serde_json::to_vec(&sentence_embeddings)?;
+    let query_params = [
+        sqlite::ValueParam::Blob(blob.as_slice()),
+        sqlite::ValueParam::Text(&paragraph.reference),
+    ];
+    conn.execute(
+        "UPDATE paragraphs SET sentence_embeddings = ? WHERE reference = ?",
+        &query_params,
+    )?;
+
+    Ok(sentence_embeddings)
+}
+
+/// Embeds a query sentence, reusing the embedding stored in
+/// `sentence_embeddings_cache` when the same sentence was embedded before.
+fn embed_sentence(sentence: &str) -> Result<Vec<f32>> {
+    let conn = Connection::open_default()?;
+    match get_cached_query_embedding(&conn, sentence) {
+        Ok(Some(embedding)) => return Ok(embedding),
+        Ok(None) => {}
+        Err(err) => error!("Failed to read query embedding cache: {:?}", err),
+    }
+
+    let embedding = embed_sentence_with(generate_normalized_embeddings, sentence)?;
+    if let Err(err) = cache_query_embedding(&conn, sentence, &embedding) {
+        error!("Failed to cache query embedding: {:?}", err);
+    }
+    Ok(embedding)
+}
+
+/// Embeds a query sentence with `generate`, which stands in for the Spin LLM
+/// host outside of it.
+fn embed_sentence_with(
+    generate: impl FnOnce(&[&str]) -> Result<EmbeddingsResult>,
+    sentence: &str,
+) -> Result<Vec<f32>> {
+    match generate(&[sentence]) {
+        Ok(er) => {
+            trace!("Generated embeddings: {:?}", er);
+            er.embeddings.into_iter().next().ok_or_else(|| {
+                anyhow::anyhow!("LLM returned empty embeddings for input: {:?}", sentence)
+            })
+        }
+        Err(err) => {
+            error!(
+                "Failed to generate embeddings when calling Spin llm: {:?}",
+                err
+            );
+            Err(err)
+        }
+    }
+}
+
+fn get_cached_query_embedding(conn: &Connection, sentence: &str) -> Result<Option<Vec<f32>>> {
+    let query_params = [
+        sqlite::ValueParam::Text(sentence),
+        sqlite::ValueParam::Text(EMBEDDING_MODEL),
+    ];
+    let result = conn.execute(
+        "SELECT embedding FROM sentence_embeddings_cache WHERE sentence = ? AND model = ?",
+        &query_params,
+    )?;
+    let embedding = result
+        .rows()
+        .next()
+        .and_then(|row| match row.get::<&ValueResult>("embedding") {
+            Some(ValueResult::Blob(b)) => Some(serde_json::from_slice(b.as_slice())),
+            _ => None,
+        })
+        .transpose()?;
+    Ok(embedding)
+}
+
+/// Stores a query embedding, then evicts the oldest entries beyond the
+/// `max_query_cache_entries` Spin variable. A limit of 0 disables the cache.
+fn cache_query_embedding(conn: &Connection, sentence: &str, embedding: &[f32]) -> Result<()> {
+    let max_entries: i64 = variable("max_query_cache_entries", 1000)?;
+    if max_entries <= 0 {
+        return Ok(());
+    }
+
+    let blob = embedding_to_blob(embedding)?;
+    let query_params = [
+        sqlite::ValueParam::Text(sentence),
+        sqlite::ValueParam::Blob(blob.as_slice()),
+        sqlite::ValueParam::Text(EMBEDDING_MODEL),
+    ];
+    conn.execute(
+        "INSERT OR REPLACE INTO sentence_embeddings_cache (sentence, embedding, model, created_at) VALUES (?, ?, ?, datetime('now'))",
+        &query_params,
+    )?;
+
+    let query_params = [sqlite::ValueParam::Integer(max_entries)];
+    conn.execute(
+        "DELETE FROM sentence_embeddings_cache WHERE sentence NOT IN (SELECT sentence FROM sentence_embeddings_cache ORDER BY created_at DESC LIMIT ?)",
+        &query_params,
+    )?;
+    Ok(())
+}
+
+fn get_compare_set(filter: &CompareFilter) -> Result<Vec<ParagraphRecord>> {
+    if filter.is_empty() {
+        let mut warm = WARM_RECORDS
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Warm paragraphs lock is poisoned"))?;
+        if let Some(records) = warm.as_ref() {
+            if records.corpus_version == corpus_version(&Connection::open_default()?)? {
+                return Ok(records.records.clone());
+            }
+            *warm = None;
+        }
+    }
+    load_compare_set(filter)
+}
+
+/// Loads every paragraph into `WARM_RECORDS`, returning how many there are.
+fn warm_compare_set() -> Result<usize> {
+    // Read first, so that a write made while loading leaves them stale.
+    let version = corpus_version(&Connection::open_default()?)?;
+    let records = load_compare_set(&CompareFilter::default())?;
+    let count = records.len();
+    *WARM_RECORDS
+        .lock()
+        .map_err(|_| anyhow::anyhow!("Warm paragraphs lock is poisoned"))? = Some(WarmRecords {
+        records,
+        corpus_version: version,
+    });
+    Ok(count)
+}
+
+fn load_compare_set(filter: &CompareFilter) -> Result<Vec<ParagraphRecord>> {
+    let mut sql_query = String::from("SELECT * FROM live_paragraphs");
+    let keyword = filter.keyword.as_deref().map(keyword_condition);
+    let (conditions, query_params) = filter_conditions(filter, &keyword);
+    if !conditions.is_empty() {
+        sql_query.push_str(" WHERE ");
+        sql_query.push_str(&conditions.join(" AND "));
+    }
+
+    match Connection::open_default()?
+        .execute(&sql_query, &query_params)?
+        .rows()
+        .map(|row| -> anyhow::Result<ParagraphRecord> { row.try_into() })
+        .collect::<anyhow::Result<Vec<ParagraphRecord>>>()
+    {
+        Ok(er) => Ok(er),
+        Err(err) => {
+            error!("Failed to get paragraphs to compare with");
+            Err(err)
+        }
+    }
+}
+
+/// Builds the conditions on paragraph columns that select the paragraphs
+/// matching `filter`, and their parameters. `keyword` is the filter's keyword
+/// passed through `keyword_condition`.
+fn filter_conditions<'a>(
+    filter: &'a CompareFilter,
+    keyword: &'a Option<(&'static str, String)>,
+) -> (Vec<&'static str>, Vec<sqlite::ValueParam<'a>>) {
+    let mut conditions: Vec<&str> = vec![];
+    let mut query_params: Vec<sqlite::ValueParam> = vec![];
+
+    if let Some(max_depth) = filter.max_depth {
+        // Rows stored before crawl_depth was tracked have NULL depth; treat
+        // them as top-level pages instead of silently dropping them.
+        conditions.push("IFNULL(crawl_depth, 0) <= ?");
+        query_params.push(sqlite::ValueParam::Integer(max_depth.into()));
+    }
+    if let Some((condition, pattern)) = &keyword {
+        conditions.push(condition);
+        query_params.push(sqlite::ValueParam::Text(pattern));
+    }
+    if let Some(min_sentences) = filter.min_sentences {
+        // Like with crawl_depth, rows stored before sentences were counted are kept.
+        conditions.push("(sentence_count IS NULL OR sentence_count >= ?)");
+        query_params.push(sqlite::ValueParam::Integer(min_sentences));
+    }
+    if let Some(domain) = &filter.domain {
+        conditions.push("source_domain = ?");
+        query_params.push(sqlite::ValueParam::Text(domain));
+    }
+    if let Some(source_type) = filter.source_type {
+        conditions.push("source_type = ?");
+        query_params.push(sqlite::ValueParam::Text(source_type.as_str()));
+    }
+    (conditions, query_params)
+}
+
+fn get_paragraph(conn: &Connection, reference: &str) -> Result<Option<Paragraph>> {
+    let query_params = [sqlite::ValueParam::Text(reference)];
+    let result = conn.execute(
+        "SELECT * FROM live_paragraphs WHERE reference = ?",
+        &query_params,
+    )?;
+    let paragraph = result.rows().next().map(Paragraph::try_from).transpose()?;
+    Ok(paragraph)
+}
+
+/// Version of the schema set up by `ensure_schema`, stored in the database's
+/// `PRAGMA user_version`. Bump it whenever `SCHEMA_COLUMNS`,
+/// `SCHEMA_MIGRATIONS`, `SCHEMA_BACKFILLS` or `FTS_TRIGGERS` change.
+const SCHEMA_VERSION: i64 = 2;
+
+/// Statements bringing databases created from older versions of `db.sql` up
+/// to date. Each must be safe to run again, as all of them run whenever
+/// `SCHEMA_VERSION` grows.
+const SCHEMA_MIGRATIONS: &[&str] = &[
+    "CREATE INDEX IF NOT EXISTS idx_paragraphs_domain ON paragraphs(source_domain)",
+    "CREATE INDEX IF NOT EXISTS idx_paragraphs_loaded_at ON paragraphs(loaded_at)",
+    "CREATE INDEX IF NOT EXISTS idx_paragraphs_retrieval_count ON paragraphs(retrieval_count)",
+    "CREATE INDEX IF NOT EXISTS idx_paragraphs_simhash ON paragraphs(simhash)",
+    "CREATE UNIQUE INDEX IF NOT EXISTS idx_paragraphs_page_id ON paragraphs(page_id)",
+    "CREATE TABLE IF NOT EXISTS batch_paragraphs (batch_id TEXT, reference TEXT, PRIMARY KEY (batch_id, reference))",
+    "CREATE TABLE IF NOT EXISTS paragraph_versions (page_id TEXT NOT NULL, version INTEGER NOT NULL, text TEXT, summary TEXT, embedding BLOB, compressed INTEGER, format TEXT, changed_at TEXT, PRIMARY KEY (page_id, version))",
+    "CREATE TABLE IF NOT EXISTS faqs (question TEXT NOT NULL, answer TEXT NOT NULL, source TEXT NOT NULL, created_at TEXT DEFAULT (datetime('now')))",
+    "CREATE TABLE IF NOT EXISTS search_feedback (query TEXT NOT NULL, reference TEXT NOT NULL, relevant INTEGER NOT NULL, judged_at TEXT DEFAULT (datetime('now')), PRIMARY KEY (query, reference))",
+    "CREATE VIEW IF NOT EXISTS live_paragraphs AS SELECT * FROM paragraphs WHERE deleted_at IS NULL",
+    "CREATE TABLE IF NOT EXISTS corpus_version (id INTEGER PRIMARY KEY CHECK (id = 1), version INTEGER NOT NULL)",
+    "INSERT OR IGNORE INTO corpus_version (id, version) VALUES (1, 0)",
+];
+
+/// Statements filling in new columns of existing rows, each with the
+/// `SCHEMA_VERSION` that added it. They scan whole tables, so each only runs on
+/// databases older than its version, before `SCHEMA_MIGRATIONS`.
+const SCHEMA_BACKFILLS: &[(i64, &str)] = &[
+    (
+        1,
+        "UPDATE paragraphs SET page_id = lower(hex(randomblob(16))) WHERE page_id IS NULL",
+    ),
+    (
+        1,
+        "UPDATE paragraphs SET source_type = CASE WHEN url IS NULL THEN 'manual' WHEN url LIKE 'http%' THEN 'url' ELSE 'file' END WHERE source_type IS NULL",
+    ),
+];
+
+/// Columns added to tables after their first release, with their types.
+/// SQLite has no `ADD COLUMN IF NOT EXISTS`, so missing ones are looked up first.
+const SCHEMA_COLUMNS: &[(&str, &str, &str)] = &[
+    ("paragraphs", "summary", "TEXT"),
+    ("paragraphs", "prompt_hash", "TEXT"),
+    ("paragraphs", "summarization_confidence", "REAL"),
+    ("paragraphs", "simhash", "INTEGER"),
+    ("paragraphs", "version", "INTEGER DEFAULT 1"),
+    ("paragraphs", "sentence_count", "INTEGER"),
+    ("paragraphs", "language_code", "TEXT"),
+    // ADD COLUMN can't default to an expression, so ids of existing rows are
+    // filled in by `SCHEMA_BACKFILLS`.
+    ("paragraphs", "page_id", "TEXT"),
+    (
+        "paragraphs",
+        "source_type",
+        "TEXT CHECK (source_type IN ('url', 'file', 'manual'))",
+    ),
+    ("paragraphs", "deleted_at", "TEXT"),
+    ("batches", "centroid", "BLOB"),
+];
+
+/// Representative filters on the indexed columns, with the index each should use.
+const PLANNED_QUERIES: &[(&str, &str)] = &[
+    (
+        "idx_paragraphs_domain",
+        "SELECT reference FROM paragraphs WHERE source_domain = 'example.com'",
+    ),
+    (
+        "idx_paragraphs_loaded_at",
+        "SELECT reference FROM paragraphs WHERE loaded_at >= datetime('now', '-7 days')",
+    ),
+    (
+        "idx_paragraphs_retrieval_count",
+        "SELECT reference FROM paragraphs ORDER BY retrieval_count DESC LIMIT 10",
+    ),
+];
+
+static SCHEMA_READY: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+/// Whether SQLite supports FTS5, in which case `paragraphs_fts` is kept in
+/// sync with `paragraphs` for `metric=bm25`. Set by `ensure_schema`.
+static FTS5_AVAILABLE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Triggers keeping `paragraphs_fts` in sync with `paragraphs`.
+const FTS_TRIGGERS: &[&str] = &[
+    "CREATE TRIGGER IF NOT EXISTS paragraphs_fts_insert AFTER INSERT ON paragraphs BEGIN
+        INSERT INTO paragraphs_fts (reference, text) VALUES (new.reference, new.text);
+    END",
+    "CREATE TRIGGER IF NOT EXISTS paragraphs_fts_update AFTER UPDATE OF reference, text ON paragraphs BEGIN
+        UPDATE paragraphs_fts SET reference = new.reference, text = new.text WHERE reference = old.reference;
+    END",
+    "CREATE TRIGGER IF NOT EXISTS paragraphs_fts_delete AFTER DELETE ON paragraphs BEGIN
+        DELETE FROM paragraphs_fts WHERE reference = old.reference;
+    END",
+];
+
+/// Applies `SCHEMA_COLUMNS`, `SCHEMA_BACKFILLS` and `SCHEMA_MIGRATIONS` to
+/// databases older than `SCHEMA_VERSION`. Up-to-date databases cost a single
+/// query, which `SCHEMA_READY` saves for the following requests of an
+/// instance; Spin 1 creates a new instance for every request, so that only
+/// helps on hosts reusing instances.
+fn ensure_schema() -> Result<()> {
+    use std::sync::atomic::Ordering;
+
+    if SCHEMA_READY.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let conn = Connection::open_default()?;
+    let result = conn.execute(
+        "SELECT user_version, EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'paragraphs_fts') AS fts FROM pragma_user_version",
+        &[],
+    )?;
+    let row = result.rows().next().context("user_version is missing")?;
+    let version = row.get::<i64>("user_version").unwrap_or_default();
+    if version >= SCHEMA_VERSION {
+        // Migrations create the full-text index whenever FTS5 is available.
+        FTS5_AVAILABLE.store(row.get::<i64>("fts") == Some(1), Ordering::Relaxed);
+        SCHEMA_READY.store(true, Ordering::Relaxed);
+        return Ok(());
+    }
+
+    info!(
+        "Migrating the database schema from version {} to {}",
+        version, SCHEMA_VERSION
+    );
+    let mut columns_added = false;
+    for (table, column, column_type) in SCHEMA_COLUMNS {
+        let query_params = [sqlite::ValueParam::Text(table)];
+        let existing: HashSet<String> = conn
+            .execute("SELECT name FROM pragma_table_info(?)", &query_params)?
+            .rows()
+            .filter_map(|row| row.get::<&str>("name").map(str::to_owned))
+            .collect();
+        // Tables missing altogether are left for `db.sql` to create.
+        if !existing.is_empty() && !existing.contains(*column) {
+            info!("Adding column {} to {}", column, table);
+            conn.execute(
+                &format!("ALTER TABLE {table} ADD COLUMN {column} {column_type}"),
+                &[],
+            )?;
+            columns_added = true;
+        }
+    }
+    // A view's `*` is expanded when it is created, so it is rebuilt with the new columns.
+    if columns_added {
+        conn.execute("DROP VIEW IF EXISTS live_paragraphs", &[])?;
+    }
+    for (added_in, backfill) in SCHEMA_BACKFILLS {
+        if version < *added_in {
+            conn.execute(backfill, &[])?;
+        }
+    }
+    for migration in SCHEMA_MIGRATIONS {
+        conn.execute(migration, &[])?;
+    }
+    let fts5 = has_fts5(&conn)?;
+    if fts5 {
+        ensure_fts_index(&conn)?;
+    }
+    // PRAGMA statements can't take parameters.
+    conn.execute(&format!("PRAGMA user_version = {SCHEMA_VERSION}"), &[])?;
+    FTS5_AVAILABLE.store(fts5, Ordering::Relaxed);
+    SCHEMA_READY.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+fn has_fts5(conn: &Connection) -> Result<bool> {
+    let fts5 = conn
+        .execute("PRAGMA compile_options", &[])?
+        .rows()
+        .any(|row| row.get::<&str>("compile_options") == Some("ENABLE_FTS5"));
+    Ok(fts5)
+}
+
+/// Creates the full-text index of the paragraphs, filling it with those
+/// stored before it existed.
+fn ensure_fts_index(conn: &Connection) -> Result<()> {
+    let exists = conn
+        .execute(
+            "SELECT name FROM sqlite_master WHERE type = 'table' AND name = 'paragraphs_fts'",
+            &[],
+        )?
+        .rows()
+        .next()
+        .is_some();
+    if !exists {
+        info!("Creating the full-text index of the paragraphs");
+        conn.execute(
+            "CREATE VIRTUAL TABLE paragraphs_fts USING fts5(reference UNINDEXED, text)",
+            &[],
+        )?;
+        conn.execute(
+            "INSERT INTO paragraphs_fts (reference, text) SELECT reference, text FROM paragraphs",
+            &[],
+        )?;
+    }
+    for trigger in FTS_TRIGGERS {
+        conn.execute(trigger, &[])?;
+    }
+    Ok(())
+}
+
+fn count_paragraphs(conn: &Connection) -> Result<i64> {
+    let total = conn
+        .execute("SELECT COUNT(*) AS total FROM live_paragraphs", &[])?
+        .rows()
+        .next()
+        .and_then(|row| row.get::<i64>("total"))
+        .unwrap_or_default();
+    Ok(total)
+}
+
+fn find_paragraph_record(conn: &Connection, reference: &str) -> Result<Option<ParagraphRecord>> {
+    let query_params = [sqlite::ValueParam::Text(reference)];
+    let result = conn.execute(
+        "SELECT * FROM live_paragraphs WHERE reference = ?",
+        &query_params,
+    )?;
+    let record = result
+        .rows()
+        .next()
+        .map(ParagraphRecord::try_from)
+        .transpose()?;
+    Ok(record)
+}
+
+fn paragraph_exists(conn: &Connection, reference: &str) -> Result<bool> {
+    let query_params = [sqlite::ValueParam::Text(reference)];
+    let result = conn.execute(
+        "SELECT reference FROM live_paragraphs WHERE reference = ?",
+        &query_params,
+    )?;
+    let exists = result.rows().next().is_some();
+    Ok(exists)
+}
+
+fn embedding_to_blob(embedding: &[f32]) -> Result<Vec<u8>> {
+    Ok(serde_json::to_vec(&json!(embedding))?)
+}
+
+fn body_bytes(req: &Request) -> &[u8] {
+    req.body().as_deref().unwrap_or_default()
+}
+
+/// Guards admin endpoints with the `X-Admin-Key` header, which must match the
+/// `admin_key` Spin variable. Returns the response to send back if the request
+/// isn't authorized.
+fn check_admin_key(req: &Request) -> Result<Option<Response>> {
+    let admin_key: String = variable("admin_key", String::new())?;
+    if admin_key.is_empty() {
+        return Ok(Some(
+            http::Response::builder()
+                .status(http::StatusCode::FORBIDDEN)
+                .body(Some("Admin endpoints are disabled, set admin_key".into()))?,
+        ));
+    }
+
+    let provided = req
+        .headers()
+        .get("X-Admin-Key")
+        .and_then(|value| value.to_str().ok());
+    if provided != Some(admin_key.as_str()) {
+        return Ok(Some(
+            http::Response::builder()
+                .status(http::StatusCode::UNAUTHORIZED)
+                .body(Some("Missing or invalid X-Admin-Key header".into()))?,
+        ));
+    }
+    Ok(None)
+}
+
+fn json_response<T: Serialize>(status: http::StatusCode, body: &T) -> Result<Response> {
+    Ok(http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Some(serde_json::to_vec(&api_value(body)?)?.into()))?)
+}
+
+/// Responds with the items as a JSON array, serializing them one at a time
+/// rather than converting a whole `Vec` with `api_value`.
+fn json_array_response<T: Serialize>(
+    status: http::StatusCode,
+    items: impl Iterator<Item = Result<T>>,
+) -> Result<Response> {
+    let legacy_snake_case = variable("legacy_snake_case", false)?;
+    let mut buffer = Vec::new();
+    let mut serializer = serde_json::Serializer::new(&mut buffer);
+    let mut array = serializer.serialize_seq(None)?;
+    for item in items {
+        let value = serde_json::to_value(item?)?;
+        if legacy_snake_case {
+            array.serialize_element(&value)?;
+        } else {
+            array.serialize_element(&camel_case_keys(value))?;
+        }
+    }
+    array.end()?;
+
+    Ok(http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Some(buffer.into()))?)
+}
+
+/// Converts a response body to its API representation, whose field names are
+/// camelCase unless the `legacy_snake_case` variable keeps the snake_case names
+/// of earlier releases. Maps keyed by data must be listed in
+/// `DATA_KEYED_FIELDS`, as every other object key is taken for a field name.
+fn api_value<T: Serialize>(body: &T) -> Result<Value> {
+    let value = serde_json::to_value(body)?;
+    if variable("legacy_snake_case", false)? {
+        return Ok(value);
+    }
+    Ok(camel_case_keys(value))
+}
+
+/// Response fields holding maps whose keys are data, such as category names,
+/// and must be returned as they are.
+const DATA_KEYED_FIELDS: &[&str] = &["scores"];
+
+fn camel_case_keys(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(name, value)| {
+                    let value = if DATA_KEYED_FIELDS.contains(&name.as_str()) {
+                        value
+                    } else {
+                        camel_case_keys(value)
+                    };
+                    (camel_case(&name), value)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.into_iter().map(camel_case_keys).collect()),
+        value => value,
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    let mut words = name.split('_');
+    let mut camel = words.next().unwrap_or_default().to_owned();
+    for word in words {
+        let mut chars = word.chars();
+        if let Some(first) = chars.next() {
+            camel.extend(first.to_uppercase());
+            camel.push_str(chars.as_str());
+        }
+    }
+    camel
+}
+
+/// Serializes `body` as JSON or CBOR, as negotiated by `ResponseFormat`.
+fn formatted_response<T: Serialize>(
+    format: ResponseFormat,
+    status: http::StatusCode,
+    body: &T,
+) -> Result<Response> {
+    match format {
+        ResponseFormat::Json => json_response(status, body),
+        ResponseFormat::Cbor => {
+            let mut buffer = Vec::new();
+            ciborium::into_writer(&api_value(body)?, &mut buffer)?;
+            Ok(http::Response::builder()
+                .status(status)
+                .header("Content-Type", "application/cbor")
+                .body(Some(buffer.into()))?)
+        }
+    }
+}
+
+fn unsupported_media_type() -> Result<Response> {
+    Ok(http::Response::builder()
+        .status(http::StatusCode::UNSUPPORTED_MEDIA_TYPE)
+        .body(Some(
+            "Supported formats are application/json and application/cbor".into(),
+        ))?)
+}
+
+fn not_found() -> Result<Response> {
+    Ok(http::Response::builder()
+        .status(http::StatusCode::NOT_FOUND)
+        .body(None)?)
+}
+
+fn bad_request(message: &str) -> Result<Response> {
+    Ok(http::Response::builder()
+        .status(http::StatusCode::BAD_REQUEST)
+        .body(Some(message.to_owned().into()))?)
+}
+
+/// Splits a long text into the segments that are embedded and stored
+/// individually by `POST /embeddings/segment`.
+fn chunk_text(text: &str, strategy: &SegmentStrategy) -> Vec<String> {
+    const WORDS_PER_CHUNK: usize = 100;
+
+    match strategy {
+        SegmentStrategy::Paragraph => sentence_splitter::split_by_paragraph(text)
+            .into_iter()
+            .map(str::to_owned)
+            .collect(),
+        SegmentStrategy::Sentence => sentence_splitter::split_by_sentence(text)
+            .into_iter()
+            .map(str::to_owned)
+            .collect(),
+        SegmentStrategy::FixedWords => sentence_splitter::split_by_words(text, WORDS_PER_CHUNK, 0),
+    }
+}
+
+/// Escapes `%`, `_` and `\` for use in a `LIKE ... ESCAPE '\'` pattern.
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+/// Extracts the lower-cased host from a URL, or `None` if it isn't one.
+fn domain_of(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority.rsplit('@').next()?.split(':').next()?;
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_lowercase())
+    }
+}
+
+/// Parses a column holding a JSON array of strings, such as `tags`.
+fn parse_string_list(value: Option<&str>) -> Vec<String> {
+    value
+        .and_then(|v| serde_json::from_str(v).ok())
+        .unwrap_or_default()
+}
+
+/// `LIKE` pattern matching rows whose `tags` contain `tag`. Tags are stored as
+/// a JSON array, so the quoted tag is matched to avoid "pub" also matching
+/// "public".
+fn tag_pattern(tag: &str) -> Result<String> {
+    Ok(format!("%{}%", escape_like(&serde_json::to_string(tag)?)))
+}
+
+/// Builds the `WHERE` condition and `LIKE` pattern for a `keyword` filter.
+/// `extracted:rust` matches the `keywords_extracted` column, anything else
+/// the paragraph text.
+fn keyword_condition(keyword: &str) -> (&'static str, String) {
+    match keyword.strip_prefix("extracted:") {
+        Some(extracted) => (
+            "keywords_extracted LIKE ? ESCAPE '\\'",
+            format!("%\"{}\"%", escape_like(&extracted.to_lowercase())),
+        ),
+        None => (
+            "text LIKE ? ESCAPE '\\'",
+            format!("%{}%", escape_like(keyword)),
+        ),
+    }
+}
+
+impl DocumentFrequencies {
+    fn load(conn: &Connection) -> Result<Self> {
+        let result = conn.execute("SELECT text FROM live_paragraphs", &[])?;
+        let mut frequencies = DocumentFrequencies {
+            documents: 0,
+            counts: HashMap::new(),
+        };
+        for text in result.rows().filter_map(|row| row.get::<&str>("text")) {
+            frequencies.documents += 1;
+            let terms: std::collections::HashSet<String> = keyword_terms(text).collect();
+            for term in terms {
+                *frequencies.counts.entry(term).or_default() += 1;
+            }
+        }
+        Ok(frequencies)
+    }
+
+    fn idf(&self, term: &str) -> f32 {
+        let documents = self.documents as f32;
+        let containing = self.counts.get(term).copied().unwrap_or(0) as f32;
+        ((1.0 + documents) / (1.0 + containing)).ln() + 1.0
+    }
+}
+
+/// Returns the `top_n` terms of `text` ranked by TF-IDF against `corpus`.
+/// With an empty corpus every IDF is 1, so this ranks by term frequency.
+fn extract_keywords(text: &str, top_n: usize, corpus: &DocumentFrequencies) -> Vec<String> {
+    let mut term_counts: HashMap<String, usize> = HashMap::new();
+    for term in keyword_terms(text) {
+        *term_counts.entry(term).or_default() += 1;
+    }
+    let total = term_counts.values().sum::<usize>() as f32;
+
+    let mut scored: Vec<(f32, String)> = term_counts
+        .into_iter()
+        .map(|(term, count)| (count as f32 / total * corpus.idf(&term), term))
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    scored.truncate(top_n);
+    scored.into_iter().map(|(_, term)| term).collect()
+}
+
+/// Lower-cased words of `text` that are worth keeping as keywords. Words are
+/// split on anything that isn't alphanumeric in any script, so non-English
+/// text tokenizes too; only English stop words are dropped.
+fn keyword_terms(text: &str) -> impl Iterator<Item = String> + '_ {
+    const STOP_WORDS: &[&str] = &[
+        "about", "also", "and", "are", "but", "can", "for", "from", "has", "have", "how", "into",
+        "its", "more", "not", "our", "than", "that", "the", "their", "them", "then", "there",
+        "these", "they", "this", "was", "were", "what", "when", "which", "who", "will", "with",
+        "you", "your",
+    ];
+
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 3)
+        .map(str::to_lowercase)
+        .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+}
+
+fn word_count(text: &str) -> i64 {
+    text.split_whitespace().count() as i64
+}
+
+fn sentence_count(text: &str) -> i64 {
+    sentence_splitter::split_by_sentence(text).len() as i64
+}
+
+fn cosine_similarity(vec1: &[f32], vec2: &[f32]) -> f32 {
+    let dot_product = vec1
+        .iter()
+        .zip(vec2.iter())
+        .map(|(x, y)| x * y)
+        .sum::<f32>();
+    let norm1 = vec1.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm2 = vec2.iter().map(|y| y * y).sum::<f32>().sqrt();
+    // The zero vector has no direction, so it is similar to nothing.
+    if norm1 == 0.0 || norm2 == 0.0 {
+        return 0.0;
+    }
+    dot_product / (norm1 * norm2)
+}
+
+/// Whether the vector can be compared by cosine similarity: its length is
+/// finite and non-zero, as the zero vector has no direction.
+fn is_valid_embedding(vector: &[f32]) -> bool {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    norm.is_finite() && norm > 0.0
+}
+
+/// Scales the vector to unit length, leaving the zero vector as it is.
+pub(crate) fn l2_normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm == 0.0 {
+        return;
+    }
+    for x in vector.iter_mut() {
+        *x /= norm;
+    }
+}
+
+pub(crate) fn l2_normalized(vector: &[f32]) -> Vec<f32> {
+    let mut normalized = vector.to_vec();
+    l2_normalize(&mut normalized);
+    normalized
+}
+
+/// Generates embeddings with the one model used throughout and scales them to
+/// unit length, so that every stored embedding is a unit vector and cosine
+/// similarity between them is just their dot product.
+fn generate_normalized_embeddings(texts: &[&str]) -> Result<EmbeddingsResult> {
+    let mut result = generate_embeddings(AllMiniLmL6V2, texts)?;
+    log_embedding_stats(texts, &result.embeddings);
+    for embedding in result.embeddings.iter_mut() {
+        l2_normalize(embedding);
+    }
+    Ok(result)
+}
+
+/// Logs the summary statistics of each embedding as returned by the model,
+/// before normalization, when built with the `debug_embeddings` feature. Zero,
+/// saturated or wrongly sized vectors stand out in them.
+#[cfg(feature = "debug_embeddings")]
+fn log_embedding_stats(texts: &[&str], embeddings: &[Vec<f32>]) {
+    const TEXT_PREVIEW_CHARS: usize = 100;
+    for (text, embedding) in texts.iter().zip(embeddings) {
+        let dimension = embedding.len().max(1) as f32;
+        let mean = embedding.iter().sum::<f32>() / dimension;
+        let variance = embedding.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / dimension;
+        let min = embedding.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = embedding.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+        let preview: String = text.chars().take(TEXT_PREVIEW_CHARS).collect();
+        trace!(
+            "Embedding of {:?}: dimension {}, mean {}, variance {}, min {}, max {}, L2 norm {}",
+            preview,
+            embedding.len(),
+            mean,
+            variance,
+            min,
+            max,
+            norm
+        );
+    }
+}
+
+#[cfg(not(feature = "debug_embeddings"))]
+fn log_embedding_stats(_texts: &[&str], _embeddings: &[Vec<f32>]) {}
+
+/// Mean of the given vectors, which must all have the same dimension.
+///
+/// Left unnormalized: its callers (batch and snapshot centroids, drift, corpus
+/// fit, cross-reference) only ever compare it by cosine similarity, which
+/// ignores length, and the centroids already stored in `batches` and
+/// `corpus_snapshots` are raw means. Use `merge_embeddings` for a concept that
+/// must be comparable to a single embedding.
+fn centroid<'a>(vectors: impl Iterator<Item = &'a [f32]>) -> Vec<f32> {
+    let mut sum: Vec<f32> = vec![];
+    let mut count = 0;
+    for vector in vectors {
+        if sum.is_empty() {
+            sum = vec![0.0; vector.len()];
+        }
+        for (s, x) in sum.iter_mut().zip(vector) {
+            *s += x;
+        }
+        count += 1;
+    }
+    sum.iter().map(|s| s / count as f32).collect()
+}
+
+/// Blends embeddings into one concept: their mean, scaled to unit length so
+/// that it's comparable to a single embedding.
+fn merge_embeddings(vecs: &[Vec<f32>]) -> Vec<f32> {
+    l2_normalized(&centroid(vecs.iter().map(Vec::as_slice)))
+}
+
+/// Returns the `top_k` dimensions contributing most to the dot product of the
+/// two vectors, ordered by the magnitude of `vec1[i] * vec2[i]`.
+fn explain_similarity(vec1: &[f32], vec2: &[f32], top_k: usize) -> Vec<(usize, f32)> {
+    let mut contributions: Vec<(usize, f32)> = vec1
+        .iter()
+        .zip(vec2.iter())
+        .map(|(x, y)| x * y)
+        .enumerate()
+        .collect();
+    contributions.sort_by(|a, b| b.1.abs().total_cmp(&a.1.abs()));
+    contributions.truncate(top_k);
+    contributions
+}
+
+fn embedding_from_row(row: &sqlite::Row) -> Result<Vec<f32>> {
+    match row
+        .get::<&ValueResult>("embedding")
+        .context("embedding column is missing")?
+    {
+        ValueResult::Blob(b) => {
+            let decompressed;
+            let blob = if row.get::<i64>("compressed") == Some(1) {
+                decompressed = decompress_blob(b)?;
+                decompressed.as_slice()
+            } else {
+                b.as_slice()
+            };
+            match row.get::<&str>("format") {
+                Some("binary") => Ok(blob
+                    .chunks_exact(4)
+                    .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                    .collect()),
+                _ => Ok(serde_json::from_value(
+                    serde_json::from_slice(blob).unwrap_or_default(),
+                )?),
+            }
+        }
+        v => anyhow::bail!("Expected Blob for embedding column, got {:?}", v),
+    }
+}
+
+/// Encoding of paragraph embeddings in the `embedding` column, set by the
+/// `embedding_format` variable. Binary stores the little-endian bytes of each
+/// `f32`, about 8 times smaller than a JSON array.
+#[derive(Clone, Copy)]
+enum EmbeddingFormat {
+    Json,
+    Binary,
+}
+
+impl EmbeddingFormat {
+    fn as_str(self) -> &'static str {
+        match self {
+            EmbeddingFormat::Json => "json",
+            EmbeddingFormat::Binary => "binary",
+        }
+    }
+}
+
+impl std::str::FromStr for EmbeddingFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> std::result::Result<Self, Self::Err> {
+        match value {
+            "json" => Ok(EmbeddingFormat::Json),
+            "binary" => Ok(EmbeddingFormat::Binary),
+            _ => Err(format!("expected json or binary, got {:?}", value)),
+        }
+    }
+}
+
+/// A paragraph embedding encoded for the `embedding` column.
+struct StoredEmbedding {
+    blob: Vec<u8>,
+    format: EmbeddingFormat,
+    compressed: bool,
+}
+
+impl StoredEmbedding {
+    fn encode(embedding: &[f32]) -> Result<Self> {
+        let format = variable("embedding_format", EmbeddingFormat::Json)?;
+        let blob = match format {
+            EmbeddingFormat::Json => embedding_to_blob(embedding)?,
+            EmbeddingFormat::Binary => embedding.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        };
+        let (blob, compressed) = compress_blob(blob)?;
+        Ok(StoredEmbedding {
+            blob,
+            format,
+            compressed,
+        })
+    }
+}
+
+/// Compresses a paragraph embedding blob when built with the `zstd` feature.
+/// Returns the blob to store and whether it is compressed.
+#[cfg(feature = "zstd")]
+fn compress_blob(blob: Vec<u8>) -> Result<(Vec<u8>, bool)> {
+    Ok((zstd::encode_all(blob.as_slice(), ZSTD_LEVEL)?, true))
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_blob(blob: Vec<u8>) -> Result<(Vec<u8>, bool)> {
+    Ok((blob, false))
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_blob(blob: &[u8]) -> Result<Vec<u8>> {
+    Ok(zstd::decode_all(blob)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_blob(_blob: &[u8]) -> Result<Vec<u8>> {
+    anyhow::bail!("Embedding is compressed, build with the zstd feature to read it")
+}
+
+impl<'a> TryFrom<sqlite::Row<'a>> for ParagraphRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(row: sqlite::Row<'a>) -> std::result::Result<Self, Self::Error> {
+        let embedding = embedding_from_row(&row)?;
+        let reference = row
+            .get::<&str>("reference")
+            .context("reference column is empty")?;
+        let text = row.get::<&str>("text").context("text column is empty")?;
+        Ok(Self {
+            reference: reference.to_owned(),
+            page_id: row.get::<&str>("page_id").map(str::to_owned),
+            text: text.to_owned(),
+            embedding,
+            crawl_depth: row.get::<i64>("crawl_depth"),
+            word_count: row.get::<i64>("word_count"),
+            sentence_count: row.get::<i64>("sentence_count"),
+            keywords_extracted: parse_string_list(row.get::<&str>("keywords_extracted")),
+            summarization_confidence: row.get::<f64>("summarization_confidence").map(|c| c as f32),
+        })
+    }
+}
+
+/// Embeds the raw text of a page, skipping summarization. Keywords are left
+/// empty, as extracting them needs the stored corpus.
+impl TryFrom<&Page> for ParagraphRecord {
+    type Error = anyhow::Error;
+
+    fn try_from(page: &Page) -> std::result::Result<Self, Self::Error> {
+        let embedding = generate_normalized_embeddings(&[page.text.as_str()])?
+            .embeddings
+            .into_iter()
+            .next()
+            .with_context(|| format!("LLM returned no embedding for {}", page.metadata.title))?;
+        Ok(Self {
+            embedding,
+            reference: page.metadata.title.clone(),
+            page_id: None,
+            text: page.text.clone(),
+            crawl_depth: Some(page.crawl.depth.into()),
+            word_count: Some(word_count(&page.text)),
+            sentence_count: Some(sentence_count(&page.text)),
+            keywords_extracted: vec![],
+            summarization_confidence: None,
+        })
+    }
+}
+
+impl<'a> TryFrom<sqlite::Row<'a>> for Paragraph {
+    type Error = anyhow::Error;
+
+    fn try_from(row: sqlite::Row<'a>) -> std::result::Result<Self, Self::Error> {
+        let reference = row
+            .get::<&str>("reference")
+            .context("reference column is empty")?;
+        let text = row.get::<&str>("text").context("text column is empty")?;
+        Ok(Self {
+            reference: reference.to_owned(),
+            page_id: row.get::<&str>("page_id").map(str::to_owned),
+            text: text.to_owned(),
+            crawl_depth: row.get::<i64>("crawl_depth"),
+            word_count: row.get::<i64>("word_count"),
+            sentence_count: row.get::<i64>("sentence_count"),
+            keywords_extracted: parse_string_list(row.get::<&str>("keywords_extracted")),
+            snippet: None,
+            summarization_confidence: row.get::<f64>("summarization_confidence").map(|c| c as f32),
+        })
+    }
+}
+
+/// An LLM summary with the hash of the prompt template that produced it, to
+/// find the summaries to regenerate when the prompt changes.
+#[derive(Serialize)]
+struct SummarizationResult {
+    summary: String,
+    /// See `summarization_confidence`.
+    confidence: f32,
+    truncated: bool,
+    #[serde(skip)]
+    prompt_hash: String,
+}
+
+/// A paragraph about to be inserted into the database.
+struct NewParagraph<'a> {
+    reference: &'a str,
+    text: &'a str,
+    embedding: Vec<f32>,
+    crawl_depth: Option<i32>,
+    url: Option<&'a str>,
+    referrer_url: Option<&'a str>,
+    source_domain: Option<String>,
+    tags: &'a [String],
+    keywords_extracted: Vec<String>,
+    /// When the page was loaded by the crawler, the time of insertion if unknown.
+    loaded_at: Option<&'a str>,
+    /// Ingest batch the paragraph was stored by, see `POST /embeddings/rollback`.
+    batch_id: Option<&'a str>,
+    /// LLM summary the embedding was generated from, if any.
+    summary: Option<&'a SummarizationResult>,
+    /// Language of the text as given by the crawler or `detect_language`.
+    language_code: Option<&'a str>,
+    source_type: SourceType,
+}
+
+/// Where a paragraph came from, stored in `source_type`.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum SourceType {
+    /// A web page.
+    Url,
+    /// A local document, whose `url` is its file path.
+    File,
+    /// Text posted directly, such as segments.
+    Manual,
+}
+
+impl SourceType {
+    /// Pages whose URL isn't a web address were ingested from files.
+    fn of_url(url: &str) -> Self {
+        if url.starts_with("http") {
+            SourceType::Url
+        } else {
+            SourceType::File
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "url" => Some(SourceType::Url),
+            "file" => Some(SourceType::File),
+            "manual" => Some(SourceType::Manual),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            SourceType::Url => "url",
+            SourceType::File => "file",
+            SourceType::Manual => "manual",
+        }
+    }
+}
+
+//AI model structure
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Paragraph {
+    reference: String,
+    /// Stable id of the paragraph, see `GET /embeddings/by-id/:page_id`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    page_id: Option<String>,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crawl_depth: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    word_count: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sentence_count: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    keywords_extracted: Vec<String>,
+    /// Sentence of `text` most relevant to the search query, if computed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snippet: Option<String>,
+    /// Confidence of the summary the embedding was generated from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    summarization_confidence: Option<f32>,
+}
+
+/// Serializes without its embedding, which would bloat most responses; wrap
+/// it in `WithEmbedding` to include it.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ParagraphRecord {
+    #[serde(skip_serializing)]
+    embedding: Vec<f32>,
+    reference: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page_id: Option<String>,
+    text: String,
+    crawl_depth: Option<i64>,
+    word_count: Option<i64>,
+    sentence_count: Option<i64>,
+    keywords_extracted: Vec<String>,
+    summarization_confidence: Option<f32>,
+}
+
+/// Serializes a record along with its embedding.
+#[derive(Serialize)]
+struct WithEmbedding<'a> {
+    #[serde(flatten)]
+    record: &'a ParagraphRecord,
+    embedding: &'a [f32],
+}
+
+impl<'a> From<&'a ParagraphRecord> for WithEmbedding<'a> {
+    fn from(record: &'a ParagraphRecord) -> Self {
+        WithEmbedding {
+            record,
+            embedding: &record.embedding,
+        }
+    }
+}
+
+impl ParagraphRecord {
+    fn to_paragraph(&self) -> Paragraph {
+        Paragraph {
+            reference: self.reference.clone(),
+            page_id: self.page_id.clone(),
+            text: self.text.clone(),
+            crawl_depth: self.crawl_depth,
+            word_count: self.word_count,
+            sentence_count: self.sentence_count,
+            keywords_extracted: self.keywords_extracted.clone(),
+            snippet: None,
+            summarization_confidence: self.summarization_confidence,
+        }
+    }
+
+    fn into_paragraph(self) -> Paragraph {
+        self.into()
+    }
+}
+
+impl From<ParagraphRecord> for Paragraph {
+    fn from(record: ParagraphRecord) -> Self {
+        Paragraph {
+            reference: record.reference,
+            page_id: record.page_id,
+            text: record.text,
+            crawl_depth: record.crawl_depth,
+            word_count: record.word_count,
+            sentence_count: record.sentence_count,
+            keywords_extracted: record.keywords_extracted,
+            snippet: None,
+            summarization_confidence: record.summarization_confidence,
+        }
+    }
+}
+
+//API input structure
+#[derive(Debug, Serialize, Deserialize)]
+struct Crawl {
+    #[serde(rename = "loadedUrl")]
+    loaded_url: String,
+    #[serde(rename = "loadedTime")]
+    loaded_time: String,
+    #[serde(rename = "referrerUrl")]
+    referrer_url: String,
+    depth: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Metadata {
+    #[serde(rename = "canonicalUrl")]
+    canonical_url: String,
+    title: String,
+    description: String,
+    author: Option<String>,
+    keywords: String,
+    #[serde(rename = "languageCode")]
+    language_code: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Page {
+    url: String,
+    crawl: Crawl,
+    metadata: Metadata,
+    #[serde(rename = "screenshotUrl")]
+    screenshot_url: Option<String>,
+    text: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+impl Page {
+    pub fn to_paragraph(&self) -> Paragraph {
+        Paragraph {
+            reference: self.url.clone(),
+            page_id: None,
+            text: self.text.clone(),
+            crawl_depth: Some(self.crawl.depth.into()),
+            word_count: Some(word_count(&self.text)),
+            sentence_count: Some(sentence_count(&self.text)),
+            keywords_extracted: vec![],
+            snippet: None,
+            summarization_confidence: None,
+        }
+    }
+}
+
+/// Response encodings that can be requested through the `Accept` header.
+#[derive(Clone, Copy)]
+enum ResponseFormat {
+    Json,
+    Cbor,
+}
+
+impl ResponseFormat {
+    /// Picks the first supported media type listed in `Accept`, defaulting to
+    /// JSON when the header is missing. Returns `None` if none is supported.
+    fn from_accept(req: &Request) -> Option<Self> {
+        let accept = match req.headers().get("Accept") {
+            Some(accept) => accept.to_str().ok()?,
+            None => return Some(ResponseFormat::Json),
+        };
+        accept.split(',').find_map(|media_type| {
+            let media_type = media_type.split(';').next().unwrap_or_default().trim();
+            match media_type.to_ascii_lowercase().as_str() {
+                "application/json" | "application/*" | "*/*" => Some(ResponseFormat::Json),
+                "application/cbor" => Some(ResponseFormat::Cbor),
+                _ => None,
+            }
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SentenceEmbedding {
+    sentence: String,
+    embedding: Vec<f32>,
+}
+
+//Similarity structures
+#[derive(Serialize)]
+struct SimilarityResultSet {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sentence: Option<String>,
+    /// Model that embedded the query, `None` when the caller passed the
+    /// embedding.
+    model: Option<&'static str>,
+    /// Answer written by the LLM that was searched for instead of `sentence`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hypothetical_doc: Option<String>,
+    /// Rephrasings of `sentence` searched for along with it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expanded_queries: Option<Vec<String>>,
+    results: Vec<SimilarityResult>,
+}
+
+#[derive(Serialize)]
+struct SimilarityResult {
+    paragraph: Paragraph,
+    similarity: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_contributing_dims: Option<Vec<DimensionContribution>>,
+    /// Score the result was picked by when diversity reranking is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mmr_score: Option<f32>,
+    /// Similarity before and after the recency boost, when it is enabled.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    raw_similarity: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    boosted_similarity: Option<f32>,
+    /// Occurrences of the `keyword` filter per word of the text.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keyword_density: Option<f32>,
+}
+
+impl SimilarityResult {
+    /// A result scored by `similarity` alone, without any of the optional
+    /// breakdowns.
+    fn new(paragraph: Paragraph, similarity: f32) -> Self {
+        SimilarityResult {
+            paragraph,
+            similarity,
+            top_contributing_dims: None,
+            mmr_score: None,
+            raw_similarity: None,
+            boosted_similarity: None,
+            keyword_density: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct DimensionContribution {
+    dim: usize,
+    contribution: f32,
+}
+
+#[derive(Deserialize)]
+struct Query {
+    sentence: String,
+    #[serde(alias = "maxDepth")]
+    max_depth: Option<i32>,
+    keyword: Option<String>,
+    /// Share of the score given to PageRank instead of similarity, from 0 to 1.
+    #[serde(alias = "pagerankWeight")]
+    pagerank_weight: Option<f32>,
+    /// Trade-off between relevance (1.0) and diversity (0.0) of the results.
+    #[serde(alias = "mmrLambda")]
+    mmr_lambda: Option<f32>,
+    /// Translates the sentence to `CORPUS_LANGUAGE` before searching.
+    #[serde(default, alias = "autoTranslate")]
+    auto_translate: bool,
+    /// Favors recently loaded pages, see `boost_recent`.
+    #[serde(default, alias = "useDateBoost")]
+    use_date_boost: bool,
+    #[serde(alias = "keywordWeight")]
+    keyword_weight: Option<f32>,
+    /// Leaves out paragraphs of fewer sentences, such as single-sentence segments.
+    #[serde(alias = "minSentences")]
+    min_sentences: Option<i64>,
+    #[serde(default, alias = "useHyde")]
+    use_hyde: bool,
+    #[serde(default)]
+    expand: bool,
+    sort: Option<ResultSort>,
+    #[serde(alias = "sourceType")]
+    source_type: Option<SourceType>,
+    metric: Option<SearchMetric>,
+}
+
+/// How `GET /embeddings?sentence=` scores paragraphs.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum SearchMetric {
+    /// Cosine similarity of the embeddings, the default.
+    Cosine,
+    /// BM25 relevance of the text, when SQLite supports FTS5.
+    Bm25,
+}
+
+/// Parameters of `GET /embeddings` telling a listing from a search.
+#[derive(Deserialize)]
+struct ListFilterQuery {
+    sentence: Option<String>,
+    #[serde(alias = "minSentences")]
+    min_sentences: Option<i64>,
+    #[serde(default, alias = "includeEmbeddings")]
+    include_embeddings: bool,
+    #[serde(alias = "sourceType")]
+    source_type: Option<SourceType>,
+}
+
+impl Query {
+    fn ranking(&self) -> RankingOptions {
+        RankingOptions {
+            pagerank_weight: self.pagerank_weight,
+            mmr_lambda: self.mmr_lambda,
+            date_boost: self.use_date_boost,
+            keyword_weight: self.keyword_weight,
+            hyde: self.use_hyde,
+            expansions: self.expand.then_some(DEFAULT_EXPANSIONS),
+            sort: self.sort,
+            snippets: false,
+        }
+    }
+
+    fn filter(&self) -> CompareFilter {
+        CompareFilter {
+            max_depth: self.max_depth,
+            keyword: self.keyword.clone(),
+            min_sentences: self.min_sentences,
+            domain: None,
+            source_type: self.source_type,
+        }
+    }
+}
+
+/// Adjustments to the order of similarity search results.
+#[derive(Default)]
+struct RankingOptions {
+    pagerank_weight: Option<f32>,
+    mmr_lambda: Option<f32>,
+    date_boost: bool,
+    /// Share of the score given to similarity instead of keyword density when
+    /// searching with a `keyword` filter.
+    keyword_weight: Option<f32>,
+    /// Searches with the embedding of an answer written by the LLM instead of
+    /// the sentence itself, see `hypothetical_document`.
+    hyde: bool,
+    /// Searches with the mean embedding of the sentence and this many
+    /// rephrasings of it, see `expand_query`.
+    expansions: Option<usize>,
+    /// Tiebreaker among equally scored results.
+    sort: Option<ResultSort>,
+    /// Attaches snippets to the top results, see `attach_snippets`. Only
+    /// worth their embedding calls for results shown to users.
+    snippets: bool,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ResultSort {
+    /// Shallower, usually more canonical, pages first.
+    DepthAsc,
+}
+
+impl RankingOptions {
+    fn validate(&self) -> std::result::Result<(), &'static str> {
+        let out_of_range = |value: Option<f32>| value.is_some_and(|v| !(0.0..=1.0).contains(&v));
+        if out_of_range(self.pagerank_weight) {
+            return Err("pagerank_weight must be between 0 and 1");
+        }
+        if out_of_range(self.mmr_lambda) {
+            return Err("mmr_lambda must be between 0 and 1");
+        }
+        if out_of_range(self.keyword_weight) {
+            return Err("keyword_weight must be between 0 and 1");
+        }
+        Ok(())
+    }
+}
+
+/// Restricts which stored paragraphs take part in a similarity search.
+#[derive(Default)]
+struct CompareFilter {
+    max_depth: Option<i32>,
+    /// Substring the paragraph text must contain, or, with an `extracted:`
+    /// prefix, a keyword that must be among its extracted keywords.
+    keyword: Option<String>,
+    min_sentences: Option<i64>,
+    /// Lower-cased host the paragraphs must have been stored from.
+    domain: Option<String>,
+    source_type: Option<SourceType>,
+}
+
+impl CompareFilter {
+    fn is_empty(&self) -> bool {
+        self.max_depth.is_none()
+            && self.keyword.is_none()
+            && self.min_sentences.is_none()
+            && self.domain.is_none()
+            && self.source_type.is_none()
+    }
+}
+
+/// How many paragraphs of the stored corpus contain each term, used to
+/// weight extracted keywords by their inverse document frequency.
+struct DocumentFrequencies {
+    documents: usize,
+    counts: HashMap<String, usize>,
+}
+
+#[derive(Deserialize)]
+struct ExplainQuery {
+    sentence: String,
+    #[serde(default)]
+    full_explain: bool,
+    #[serde(default = "default_explain_top_dims")]
+    top_dims: usize,
+}
+
+fn default_explain_top_dims() -> usize {
+    10
+}
+
+#[derive(Deserialize)]
+struct EmbedQuery {
+    texts: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct EmbedResult {
+    embeddings: Vec<Vec<f32>>,
+    model: &'static str,
+    dim: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Prompt {
+    name: String,
+    template: String,
+}
+
+#[derive(Deserialize)]
+struct AnchorGraphQuery {
+    domain: String,
+}
+
+#[derive(Serialize)]
+struct DiversityScore {
+    mean_pairwise_similarity: f32,
+    std_dev: f32,
+    min: f32,
+    max: f32,
+    sample_size: usize,
+}
+
+#[derive(Deserialize)]
+struct AnchorSearchQuery {
+    start: String,
+    sentence: String,
+    #[serde(default = "default_max_hops")]
+    max_hops: usize,
+    #[serde(default = "default_anchor_top_k")]
+    top_k: usize,
+}
+
+fn default_max_hops() -> usize {
+    3
+}
+
+fn default_anchor_top_k() -> usize {
+    1
+}
+
+#[derive(Serialize)]
+struct AnchorSearchResult {
+    start: String,
+    max_hops: usize,
+    results_by_depth: Vec<Vec<SimilarityResult>>,
+}
+
+#[derive(Serialize)]
+struct AnchorGraph {
+    nodes: Vec<AnchorNode>,
+    edges: Vec<AnchorEdge>,
+}
+
+#[derive(Serialize)]
+struct AnchorNode {
+    id: String,
+    depth: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct AnchorEdge {
+    source: String,
+    target: String,
+}
+
+#[derive(Deserialize)]
+struct ClassifyQuery {
+    reference: String,
+    categories: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct Classification {
+    reference: String,
+    category: String,
+    /// Similarity of the paragraph to each category label.
+    scores: HashMap<String, f32>,
+}
+
+#[derive(Deserialize)]
+struct CompareQuery {
+    text_a: String,
+    text_b: String,
+}
+
+#[derive(Deserialize)]
+struct Feedback {
+    query: String,
+    reference: String,
+    relevant: bool,
+}
+
+#[derive(Deserialize)]
+struct FeedbackMetricsQuery {
+    query: String,
+    #[serde(default = "default_feedback_k")]
+    k: usize,
+}
+
+fn default_feedback_k() -> usize {
+    5
+}
+
+#[derive(Serialize)]
+struct FeedbackMetrics {
+    precision_at_k: f32,
+    recall_at_k: f32,
+    k: usize,
+    judged_count: usize,
+}
+
+#[derive(Deserialize)]
+struct ScoreDatasetQuery {
+    queries: Vec<DatasetQuestion>,
+    #[serde(default = "default_dataset_k")]
+    k: usize,
+}
+
+fn default_dataset_k() -> usize {
+    5
+}
+
+#[derive(Deserialize)]
+struct DatasetQuestion {
+    question: String,
+    expected_references: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct DatasetScore {
+    mean_hit_at_k: f32,
+    mean_reciprocal_rank: f32,
+    k: usize,
+    query_count: usize,
+}
+
+#[derive(Deserialize)]
+struct QueryExpansionQuery {
+    question: String,
+    #[serde(default = "default_expansions")]
+    expansions: usize,
+}
+
+fn default_expansions() -> usize {
+    DEFAULT_EXPANSIONS
+}
+
+#[derive(Deserialize)]
+struct HypotheticalDocQuery {
+    question: String,
+}
+
+#[derive(Deserialize)]
+struct ExplainCosineQuery {
+    query: String,
+    /// Stored paragraph to compare with, instead of `text`.
+    reference: Option<String>,
+    text: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CosineExplanation {
+    contributions: Vec<f32>,
+    similarity: f32,
+}
+
+#[derive(Serialize)]
+struct CompareResult {
+    similarity: f32,
+    embedding_a_dim: usize,
+    embedding_b_dim: usize,
+    model: &'static str,
+}
+
+#[derive(Deserialize)]
+struct BackfillQuery {
+    #[serde(default = "default_backfill_batch_size")]
+    batch_size: i64,
+}
+
+fn default_backfill_batch_size() -> i64 {
+    20
+}
+
+#[derive(Deserialize)]
+struct BackfillNeededQuery {
+    prompt_hash: Option<String>,
+}
+
+#[derive(Serialize)]
+struct BackfillNeeded {
+    prompt_hash: String,
+    count: i64,
+}
+
+#[derive(Serialize)]
+struct BackfillResult {
+    processed: usize,
+    remaining: i64,
+}
+
+#[derive(Deserialize)]
+struct CacheQuery {
+    older_than: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TextDuplicates {
+    /// The BLAKE3 `content_hash` of the text, stored since ingest skips
+    /// unchanged pages by it.
+    text_hash: String,
+    references: Vec<String>,
+    count: usize,
+}
+
+#[derive(Deserialize)]
+struct DedupQuery {
+    #[serde(default = "default_dedup_keep")]
+    keep: String,
+}
+
+fn default_dedup_keep() -> String {
+    "first".to_owned()
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    message: &'static str,
+}
+
+#[derive(Serialize)]
+struct DeleteResult {
+    deleted: i64,
+}
+
+#[derive(Deserialize)]
+struct BatchDeleteByDomainQuery {
+    domains: Vec<String>,
+    #[serde(default)]
+    hard_delete: bool,
+}
+
+#[derive(Serialize)]
+struct DomainDeleteResult {
+    domain: String,
+    deleted: i64,
+}
+
+#[derive(Serialize)]
+struct BatchDeleteResult {
+    results: Vec<DomainDeleteResult>,
+}
+
+#[derive(Deserialize)]
+struct PreviewQuery {
+    q: String,
+    #[serde(default = "default_preview_top_k")]
+    top_k: usize,
+}
+
+fn default_preview_top_k() -> usize {
+    5
+}
+
+#[derive(Serialize)]
+struct PreviewResult {
+    reference: String,
+    similarity: f32,
+    preview: String,
+}
+
+#[derive(Deserialize)]
+struct SuggestRelatedQuery {
+    q: String,
+}
+
+#[derive(Serialize)]
+struct RelatedSuggestions {
+    suggestions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ExcludeQuery {
+    q: String,
+    exclude_reference: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NeighborsQuery {
+    reference: String,
+    #[serde(default = "default_radius")]
+    radius: f32,
+}
+
+fn default_radius() -> f32 {
+    0.8
+}
+
+#[derive(Serialize)]
+struct NeighborsResult {
+    reference: String,
+    radius: f32,
+    neighbors: Vec<SimilarityResult>,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct ExcludeResultSet {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    excluded_reference: Option<String>,
+    results: Vec<SimilarityResult>,
+}
+
+#[derive(Deserialize)]
+struct MatrixQuery {
+    references: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct SimilarityMatrix {
+    labels: Vec<String>,
+    matrix: Vec<Vec<f32>>,
+}
+
+#[derive(Deserialize)]
+struct CrossReferenceQuery {
+    source_domain: String,
+    target_domain: String,
+    #[serde(default = "default_cross_reference_top_k")]
+    top_k: usize,
+}
+
+fn default_cross_reference_top_k() -> usize {
+    5
+}
+
+#[derive(Serialize)]
+struct CrossReferenceResult {
+    target_centroid_dim: usize,
+    results: Vec<SimilarityResult>,
+}
+
+#[derive(Deserialize)]
+struct VectorMathQuery {
+    operation: VectorOperation,
+    references: Vec<String>,
+    /// Factor of the `scale` operation.
+    #[serde(default = "default_scale_factor")]
+    factor: f32,
+}
+
+fn default_scale_factor() -> f32 {
+    1.0
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum VectorOperation {
+    /// Sum of all the embeddings.
+    Add,
+    /// The first embedding minus all the others.
+    Subtract,
+    /// The only embedding times `factor`.
+    Scale,
+}
+
+#[derive(Serialize)]
+struct VectorMathResult {
+    embedding: Vec<f32>,
+    nearest: Option<SimilarityResult>,
+}
+
+#[derive(Deserialize)]
+struct PipelineQuery {
+    steps: Vec<PipelineStep>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum PipelineStep {
+    KeywordFilter(KeywordFilterStep),
+    SemanticSearch(SemanticSearchStep),
+    MmrRerank(MmrRerankStep),
+    DateBoost(DateBoostStep),
+    TopK(TopKStep),
+}
+
+impl PipelineStep {
+    fn as_search_step(&self) -> &dyn SearchStep {
+        match self {
+            PipelineStep::KeywordFilter(step) => step,
+            PipelineStep::SemanticSearch(step) => step,
+            PipelineStep::MmrRerank(step) => step,
+            PipelineStep::DateBoost(step) => step,
+            PipelineStep::TopK(step) => step,
+        }
+    }
+
+    fn validate(&self) -> Option<&'static str> {
+        match self {
+            PipelineStep::MmrRerank(step) if !(0.0..=1.0).contains(&step.lambda) => {
+                Some("lambda must be between 0 and 1")
+            }
+            PipelineStep::DateBoost(step) if step.decay_rate.is_some_and(|rate| rate < 0.0) => {
+                Some("decay_rate must not be negative")
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct KeywordFilterStep {
+    keyword: String,
+}
+
+#[derive(Deserialize)]
+struct SemanticSearchStep {
+    sentence: String,
+    #[serde(default = "default_pipeline_top_k")]
+    top_k: usize,
+}
+
+fn default_pipeline_top_k() -> usize {
+    50
+}
+
+#[derive(Deserialize)]
+struct MmrRerankStep {
+    lambda: f32,
+}
+
+#[derive(Deserialize)]
+struct DateBoostStep {
+    /// Defaults to the `date_decay_rate` variable.
+    decay_rate: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct TopKStep {
+    k: usize,
+}
+
+#[derive(Deserialize)]
+struct VectorQuery {
     embedding: Vec<f32>,
+    #[serde(default = "default_vector_top_k")]
+    top_k: usize,
+}
+
+fn default_vector_top_k() -> usize {
+    10
+}
+
+#[derive(Deserialize)]
+struct RerankQuery {
+    question: String,
+    #[serde(default = "default_rerank_top_k")]
+    top_k: usize,
+    #[serde(default = "default_rerank_candidates")]
+    candidates: usize,
+}
+
+fn default_rerank_top_k() -> usize {
+    5
+}
+
+fn default_rerank_candidates() -> usize {
+    20
+}
+
+#[derive(Serialize)]
+struct RerankResultSet {
+    question: String,
+    results: Vec<RerankResult>,
+}
+
+#[derive(Serialize)]
+struct RerankResult {
+    paragraph: Paragraph,
+    semantic_score: f32,
+    llm_rerank_score: f32,
+}
+
+#[derive(Deserialize)]
+struct SummarizeParams {
+    #[serde(default)]
+    model: SummaryModel,
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum SummaryModel {
+    #[default]
+    Llama2Chat,
+    CodellamaInstruct,
+}
+
+impl SummaryModel {
+    fn inferencing_model(self) -> InferencingModel<'static> {
+        match self {
+            SummaryModel::Llama2Chat => InferencingModel::Llama2Chat,
+            SummaryModel::CodellamaInstruct => InferencingModel::CodellamaInstruct,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SummarizeQuery {
+    text: String,
+}
+
+#[derive(Deserialize)]
+struct TranslateQuery {
+    sentence: String,
+    #[serde(default = "default_target_language")]
+    target_language: String,
+}
+
+fn default_target_language() -> String {
+    CORPUS_LANGUAGE.to_owned()
+}
+
+#[derive(Serialize)]
+struct TranslatedResultSet {
+    translated_query: String,
+    results: Vec<SimilarityResult>,
+}
+
+#[derive(Deserialize)]
+struct AbTestQuery {
+    question: String,
+    strategy_a: SearchStrategy,
+    strategy_b: SearchStrategy,
+}
+
+#[derive(Deserialize)]
+struct SearchStrategy {
+    metric: SimilarityMetric,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SimilarityMetric {
+    Cosine,
+    Euclidean,
+}
+
+impl SimilarityMetric {
+    /// Higher is more similar for every metric; euclidean distance `d` is
+    /// mapped to `1 / (1 + d)`.
+    fn similarity(self, vec1: &[f32], vec2: &[f32]) -> f32 {
+        match self {
+            SimilarityMetric::Cosine => cosine_similarity(vec1, vec2),
+            SimilarityMetric::Euclidean => {
+                let distance = vec1
+                    .iter()
+                    .zip(vec2.iter())
+                    .map(|(x, y)| (x - y) * (x - y))
+                    .sum::<f32>()
+                    .sqrt();
+                1.0 / (1.0 + distance)
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AbTestResultSet {
+    id: String,
+    question: String,
+    strategy_a: Vec<SimilarityResult>,
+    strategy_b: Vec<SimilarityResult>,
+}
+
+#[derive(Deserialize)]
+struct AbTestPreferenceQuery {
+    preferred: PreferredStrategy,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum PreferredStrategy {
+    A,
+    B,
+}
+
+impl PreferredStrategy {
+    fn as_str(self) -> &'static str {
+        match self {
+            PreferredStrategy::A => "a",
+            PreferredStrategy::B => "b",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AbTest {
+    id: String,
+    question: String,
+    strategy_a_refs: Vec<String>,
+    strategy_b_refs: Vec<String>,
+    preferred: Option<String>,
+    created_at: String,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    limit: Option<i64>,
+    #[serde(default)]
+    order: HistoryOrder,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum HistoryOrder {
+    #[default]
+    Popular,
+    Recent,
+}
+
+#[derive(Deserialize)]
+struct HotQueriesQuery {
+    limit: Option<i64>,
+    #[serde(default = "default_hot_queries_since")]
+    since: String,
+}
+
+fn default_hot_queries_since() -> String {
+    "7d".to_owned()
+}
+
+#[derive(Serialize)]
+struct HotQuery {
+    query: String,
+    count: i64,
+    last_queried: String,
+}
+
+#[derive(Serialize)]
+struct SearchHistoryEntry {
+    sentence: String,
+    count: i64,
+    last_queried: String,
+}
+
+#[derive(Serialize)]
+struct DomainCount {
+    domain: String,
+    count: i64,
+}
+
+#[derive(Deserialize)]
+struct AnomalyQuery {
+    #[serde(default = "default_anomaly_threshold")]
+    threshold: f32,
+}
+
+fn default_anomaly_threshold() -> f32 {
+    0.3
+}
+
+#[derive(Serialize)]
+struct Anomaly {
     reference: String,
-    text: String,
+    domain_similarity: f32,
 }
 
-//API input structure
-#[derive(Debug, Serialize, Deserialize)]
-struct Crawl {
-    #[serde(rename = "loadedUrl")]
-    loaded_url: String,
-    #[serde(rename = "loadedTime")]
-    loaded_time: String,
-    #[serde(rename = "referrerUrl")]
-    referrer_url: String,
-    depth: i32,
+#[derive(Deserialize)]
+struct SnapshotEmbeddingsQuery {
+    name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Metadata {
-    #[serde(rename = "canonicalUrl")]
-    canonical_url: String,
-    title: String,
-    description: String,
-    author: Option<String>,
-    keywords: String,
-    #[serde(rename = "languageCode")]
-    language_code: String,
+#[derive(Serialize)]
+struct EmbeddingsSnapshot {
+    name: String,
+    paragraph_count: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Page {
-    url: String,
-    crawl: Crawl,
-    metadata: Metadata,
-    #[serde(rename = "screenshotUrl")]
-    screenshot_url: Option<String>,
+/// A paragraph as saved by `POST /embeddings/snapshot-embeddings`. Columns
+/// derived from the text, such as the word count, are recomputed on restore,
+/// and retrieval statistics aren't kept.
+#[derive(Serialize, Deserialize)]
+struct SnapshotParagraph {
+    reference: String,
+    page_id: Option<String>,
     text: String,
+    embedding: Vec<f32>,
+    crawl_depth: Option<i32>,
+    url: Option<String>,
+    referrer_url: Option<String>,
+    source_domain: Option<String>,
+    tags: Vec<String>,
+    keywords_extracted: Vec<String>,
+    loaded_at: Option<String>,
+    batch_id: Option<String>,
+    summary: Option<String>,
+    prompt_hash: Option<String>,
+    summarization_confidence: Option<f32>,
+    language_code: Option<String>,
+    source_type: Option<String>,
 }
-impl Page {
-    pub fn to_paragraph(&self) -> Paragraph {
-        Paragraph {
-            reference: self.url.clone(),
-            text: self.text.clone(),
+
+impl TryFrom<&sqlite::Row<'_>> for SnapshotParagraph {
+    type Error = anyhow::Error;
+
+    fn try_from(row: &sqlite::Row<'_>) -> Result<Self, Self::Error> {
+        let text = |column| row.get::<&str>(column).map(str::to_owned);
+        Ok(SnapshotParagraph {
+            reference: text("reference").context("reference column is missing")?,
+            page_id: text("page_id"),
+            text: text("text").context("text column is missing")?,
+            embedding: embedding_from_row(row)?,
+            crawl_depth: row.get::<i64>("crawl_depth").map(|d| d as i32),
+            url: text("url"),
+            referrer_url: text("referrer_url"),
+            source_domain: text("source_domain"),
+            tags: parse_string_list(row.get::<&str>("tags")),
+            keywords_extracted: parse_string_list(row.get::<&str>("keywords_extracted")),
+            loaded_at: text("loaded_at"),
+            batch_id: text("batch_id"),
+            summary: text("summary"),
+            prompt_hash: text("prompt_hash"),
+            summarization_confidence: row.get::<f64>("summarization_confidence").map(|c| c as f32),
+            language_code: text("language_code"),
+            source_type: text("source_type"),
+        })
+    }
+}
+
+impl SnapshotParagraph {
+    fn restore(&self, conn: &Connection) -> Result<()> {
+        let summary = self.summary.as_ref().map(|summary| SummarizationResult {
+            summary: summary.clone(),
+            confidence: self.summarization_confidence.unwrap_or(1.0),
+            truncated: false,
+            prompt_hash: self.prompt_hash.clone().unwrap_or_default(),
+        });
+        let source_type = self
+            .source_type
+            .as_deref()
+            .and_then(SourceType::parse)
+            .unwrap_or_else(|| SourceType::of_url(self.url.as_deref().unwrap_or_default()));
+        insert_paragraph(
+            conn,
+            &NewParagraph {
+                reference: &self.reference,
+                text: &self.text,
+                embedding: self.embedding.clone(),
+                crawl_depth: self.crawl_depth,
+                url: self.url.as_deref(),
+                referrer_url: self.referrer_url.as_deref(),
+                source_domain: self.source_domain.clone(),
+                tags: &self.tags,
+                keywords_extracted: self.keywords_extracted.clone(),
+                loaded_at: self.loaded_at.as_deref(),
+                batch_id: self.batch_id.as_deref(),
+                summary: summary.as_ref(),
+                language_code: self.language_code.as_deref(),
+                source_type,
+            },
+        )?;
+        if let Some(page_id) = &self.page_id {
+            let query_params = [
+                sqlite::ValueParam::Text(page_id),
+                sqlite::ValueParam::Text(&self.reference),
+            ];
+            conn.execute(
+                "UPDATE paragraphs SET page_id = ? WHERE reference = ?",
+                &query_params,
+            )?;
         }
+        Ok(())
     }
 }
 
-//Similarity structures
 #[derive(Serialize)]
-struct SimilarityResultSet {
+struct CorpusSnapshot {
+    snapshot_at: String,
+    paragraph_count: i64,
+}
+
+#[derive(Deserialize)]
+struct DriftQuery {
+    since: String,
+}
+
+#[derive(Serialize)]
+struct CorpusDrift {
+    /// Cosine similarity of the current and the snapshot centroid; 1 means
+    /// the corpus hasn't drifted.
+    drift_score: f32,
+    snapshot_at: String,
+    current_count: i64,
+    snapshot_count: i64,
+}
+
+#[derive(Deserialize)]
+struct CoverageQuery {
+    domain: String,
+    total: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct Coverage {
+    indexed: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    coverage_pct: Option<f64>,
+}
+
+#[derive(Deserialize)]
+struct IngestParams {
+    #[serde(default)]
+    warm: bool,
+    /// Embeds the page text directly instead of its LLM summary.
+    #[serde(default)]
+    skip_summarize: bool,
+    /// Length of the summaries that get embedded, from 1 to 10 sentences.
+    #[serde(default = "default_summary_sentences")]
+    summary_sentences: usize,
+}
+
+fn default_summary_sentences() -> usize {
+    SUMMARY_SENTENCES
+}
+
+impl Default for IngestParams {
+    fn default() -> Self {
+        IngestParams {
+            warm: false,
+            skip_summarize: false,
+            summary_sentences: SUMMARY_SENTENCES,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct WarmResult {
+    paragraphs_loaded: usize,
+}
+
+#[derive(Serialize)]
+struct PageRankResult {
+    paragraphs_ranked: usize,
+}
+
+#[cfg(feature = "zstd")]
+#[derive(Serialize)]
+struct CompressResult {
+    compressed: usize,
+}
+
+#[derive(Serialize)]
+struct QueryPlan {
+    query: &'static str,
+    index: &'static str,
+    uses_index: bool,
+    details: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AdminResult {
+    status: &'static str,
+    message: &'static str,
+}
+
+#[derive(Deserialize)]
+struct QuizQuery {
+    tag: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct FaqQuery {
+    tag: Option<String>,
+    #[serde(default = "default_faq_count")]
+    count: usize,
+}
+
+fn default_faq_count() -> usize {
+    5
+}
+
+#[derive(Serialize)]
+struct Faq {
+    question: String,
+    answer: String,
+    source: String,
+}
+
+#[derive(Serialize)]
+struct Quiz {
+    question: String,
+    source_reference: String,
+    source_summary: String,
+}
+
+#[derive(Serialize)]
+struct CorpusSummary {
+    corpus_summary: String,
+    sample_size: usize,
+    total_paragraphs: i64,
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ParagraphPage {
+    total: i64,
+    items: Vec<Paragraph>,
+}
+
+#[derive(Deserialize)]
+struct AggregateParams {
+    #[serde(default)]
+    aggregate: AggregateBy,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum AggregateBy {
+    #[default]
+    Domain,
+    Tag,
+}
+
+#[derive(Deserialize)]
+struct AggregateQuery {
     sentence: String,
-    results: Vec<SimilarityResult>,
+    #[serde(default = "default_aggregate_top_k")]
+    top_k: usize,
+}
+
+fn default_aggregate_top_k() -> usize {
+    20
 }
 
 #[derive(Serialize)]
-struct SimilarityResult {
+struct ClusterResult {
+    cluster: String,
+    representative: Paragraph,
+    score: f32,
+    member_count: usize,
+}
+
+#[derive(Serialize)]
+struct IngestResult {
+    stored: usize,
+    unchanged: usize,
+    /// Pages skipped as near-duplicates of another stored page, see `simhash`.
+    skipped_duplicates: usize,
+    /// Pages that failed to be summarized, embedded or stored, each listed in
+    /// `errors`.
+    failed: usize,
+    errors: Vec<IngestError>,
+    /// Batch the stored paragraphs belong to, if any pages needed storing.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    batch_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct IngestError {
+    reference: String,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct Batch {
+    batch_id: String,
+    created_at: String,
+    paragraphs: i64,
+}
+
+#[derive(Deserialize)]
+struct CompareBatchesQuery {
+    batch_a: String,
+    batch_b: String,
+}
+
+struct BatchContents {
+    members: HashSet<String>,
+    /// Missing for batches stored before centroids were recorded.
+    centroid: Option<Vec<f32>>,
+}
+
+#[derive(Serialize)]
+struct BatchComparison {
+    only_in_a: usize,
+    only_in_b: usize,
+    in_both: usize,
+    centroid_similarity: Option<f32>,
+}
+
+#[derive(Deserialize)]
+struct RollbackQuery {
+    batch_id: String,
+}
+
+#[derive(Deserialize)]
+struct IngestUrlQuery {
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct IngestSitemapQuery {
+    sitemap_url: String,
+}
+
+#[derive(Serialize)]
+struct IngestSitemapResult {
+    /// Pages stored by this request.
+    queued: usize,
+    already_indexed: usize,
+    /// Pages that failed to be fetched or ingested, each listed in `errors`.
+    failed: usize,
+    errors: Vec<IngestError>,
+}
+
+#[derive(Serialize)]
+struct IngestUrlResult {
+    reference: String,
+    text_length: usize,
+    status: &'static str,
+}
+
+#[derive(Deserialize)]
+struct SegmentRequest {
+    text: String,
+    reference_prefix: String,
+    #[serde(default)]
+    strategy: SegmentStrategy,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "snake_case")]
+enum SegmentStrategy {
+    #[default]
+    Paragraph,
+    Sentence,
+    FixedWords,
+}
+
+#[derive(Serialize)]
+struct SegmentResult {
+    segments_stored: usize,
+}
+
+#[derive(Deserialize)]
+struct UrlQuery {
+    url: String,
+    #[serde(default, alias = "withCorpusFit")]
+    with_corpus_fit: bool,
+}
+
+#[derive(Deserialize)]
+struct CorpusFitQuery {
+    #[serde(default, alias = "withCorpusFit")]
+    with_corpus_fit: bool,
+}
+
+/// A paragraph with its similarity to the corpus centroid, where values
+/// below 0.3 suggest it doesn't belong in the corpus.
+#[derive(Serialize)]
+struct WithCorpusFit {
+    #[serde(flatten)]
     paragraph: Paragraph,
-    similarity: f32,
+    corpus_similarity: f32,
 }
 
+//Embedding override structures
 #[derive(Deserialize)]
-struct Query {
-    sentence: String,
+struct EmbeddingOverride {
+    embedding: Vec<f32>,
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct TextUpdate {
+    text: String,
+}
+
+#[derive(Serialize)]
+struct TextUpdateResult {
+    unchanged: bool,
+}
+
+#[derive(Serialize)]
+struct ParagraphTimeline {
+    reference: String,
+    current_version: i64,
+    versions: Vec<ParagraphVersion>,
+}
+
+#[derive(Serialize)]
+struct ParagraphVersion {
+    version: i64,
+    text: Option<String>,
+    summary: Option<String>,
+    /// When this version was replaced by the next one.
+    changed_at: Option<String>,
+}
+
+impl ParagraphVersion {
+    fn from_row(row: &sqlite::Row) -> Self {
+        ParagraphVersion {
+            version: row.get::<i64>("version").unwrap_or(1),
+            text: row.get::<&str>("text").map(str::to_owned),
+            summary: row.get::<&str>("summary").map(str::to_owned),
+            changed_at: row.get::<&str>("changed_at").map(str::to_owned),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ArchivedParagraph {
+    #[serde(flatten)]
+    version: ParagraphVersion,
+    embedding: Vec<f32>,
+}
+
+#[derive(Serialize)]
+struct StoredEmbeddingResult {
+    reference: String,
+    embedding: Vec<f32>,
+    dim: usize,
+    model: String,
+}
+
+#[derive(Serialize)]
+struct EmbeddingOverrideResult {
+    reference: String,
+    embedding_dim: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embed_sentence_errors_on_empty_embeddings_for_empty_input() {
+        let generate = |texts: &[&str]| -> Result<EmbeddingsResult> {
+            assert_eq!(texts, [""]);
+            Ok(EmbeddingsResult {
+                embeddings: vec![],
+                usage: spin_sdk::llm::EmbeddingsUsage {
+                    prompt_token_count: 0,
+                },
+            })
+        };
+        let err = embed_sentence_with(generate, "").unwrap_err();
+        assert!(err.to_string().contains("empty embeddings"));
+    }
+
+    #[test]
+    fn paragraph_record_errors_on_null_embedding() {
+        let result = sqlite::QueryResult {
+            columns: vec![
+                "reference".to_owned(),
+                "text".to_owned(),
+                "embedding".to_owned(),
+            ],
+            rows: vec![sqlite::RowResult {
+                values: vec![
+                    ValueResult::Text("https://example.com".to_owned()),
+                    ValueResult::Text("Some text".to_owned()),
+                    ValueResult::Null,
+                ],
+            }],
+        };
+        let row = result.rows().next().unwrap();
+        let err = ParagraphRecord::try_from(row).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("Expected Blob for embedding column"));
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn merge_embeddings_of_a_unit_vector_with_itself_is_that_vector() {
+        let v = vec![0.6, 0.0, -0.8];
+        assert_close(&merge_embeddings(&[v.clone(), v.clone()]), &v);
+    }
+
+    #[test]
+    fn merge_embeddings_of_orthogonal_unit_vectors_is_their_bisector() {
+        let bisector = std::f32::consts::FRAC_1_SQRT_2;
+        let merged = merge_embeddings(&[vec![1.0, 0.0], vec![0.0, 1.0]]);
+        assert_close(&merged, &[bisector, bisector]);
+    }
+
+    #[test]
+    fn is_fetchable_url_rejects_local_and_private_hosts() {
+        for url in [
+            "http://localhost/",
+            "http://127.0.0.1:3000/admin",
+            "http://user@10.0.0.5/",
+            "https://192.168.1.1/",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/",
+            "http://[fd00::1]/",
+            "http://[::ffff:127.0.0.1]/",
+            "http://2130706433/",
+            "http://0x7f.1/",
+            "ftp://example.com/",
+        ] {
+            assert!(!is_fetchable_url(url), "{}", url);
+        }
+        for url in [
+            "https://example.com/article",
+            "http://93.184.216.34/",
+            "https://cafe.be:8443/?q=1",
+        ] {
+            assert!(is_fetchable_url(url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn is_write_request_covers_every_mutating_route() {
+        use http::Method;
+
+        let writes = [
+            (Method::POST, "/embeddings"),
+            (Method::POST, "/embeddings/"),
+            (Method::POST, "/embeddings/ingest-url"),
+            (Method::POST, "/embeddings/ingest-sitemap"),
+            (Method::POST, "/embeddings/segment"),
+            (Method::POST, "/embeddings/explain-corpus"),
+            (Method::POST, "/embeddings/generate-faq"),
+            (Method::POST, "/embeddings/ab-test"),
+            (Method::POST, "/embeddings/ab-test/9f1c/result"),
+            (Method::POST, "/embeddings/summarize"),
+            (Method::POST, "/embeddings/feedback"),
+            (Method::POST, "/embeddings/vacuum"),
+            (Method::POST, "/embeddings/warm"),
+            (Method::POST, "/embeddings/snapshot"),
+            (Method::POST, "/embeddings/snapshot-embeddings"),
+            (Method::POST, "/embeddings/restore/nightly"),
+            (Method::POST, "/embeddings/compute-pagerank"),
+            (Method::POST, "/embeddings/prompts"),
+            (Method::POST, "/embeddings/rollback"),
+            (Method::POST, "/embeddings/compress"),
+            (Method::POST, "/embeddings/batch-delete-by-domain"),
+            (Method::POST, "/embeddings/backfill-summaries"),
+            (Method::PUT, "/embeddings/page/embedding"),
+            (Method::PATCH, "/embeddings/page/text"),
+            (Method::DELETE, "/embeddings/query-cache"),
+            (Method::DELETE, "/embeddings/domain/example.com"),
+            (Method::DELETE, "/embeddings/text-duplicates"),
+            (Method::DELETE, "/embeddings/page"),
+        ];
+        for (method, path) in writes {
+            assert!(is_write_request(&method, path), "{} {}", method, path);
+        }
+
+        assert!(!is_write_request(&Method::GET, "/embeddings"));
+        assert!(!is_write_request(
+            &Method::GET,
+            "/embeddings/ab-test/9f1c/result"
+        ));
+        for path in READ_ONLY_POST_ROUTES {
+            assert!(!is_write_request(&Method::POST, path), "POST {}", path);
+        }
+    }
+
+    /// Answers the version lookup of `paragraph_version` with `version` and
+    /// records every statement run.
+    struct RecordingDatabase {
+        version: Option<i64>,
+        statements: std::cell::RefCell<Vec<(String, String)>>,
+    }
+
+    impl Database for RecordingDatabase {
+        fn execute(
+            &self,
+            query: &str,
+            parameters: &[sqlite::ValueParam],
+        ) -> Result<sqlite::QueryResult> {
+            self.statements
+                .borrow_mut()
+                .push((query.to_owned(), format!("{:?}", parameters)));
+            let rows = match self.version {
+                Some(version) if query.contains("AS version FROM live_paragraphs") => {
+                    vec![sqlite::RowResult {
+                        values: vec![ValueResult::Integer(version)],
+                    }]
+                }
+                _ => vec![],
+            };
+            Ok(sqlite::QueryResult {
+                columns: vec!["version".to_owned()],
+                rows,
+            })
+        }
+    }
+
+    #[test]
+    fn reingest_archives_the_replaced_version() {
+        let db = RecordingDatabase {
+            version: Some(2),
+            statements: Default::default(),
+        };
+        archive_replaced_paragraph(&db, "https://example.com").unwrap();
+        let statements = db.statements.borrow();
+        let (query, parameters) = statements.last().unwrap();
+        assert!(query.contains("INTO paragraph_versions"));
+        assert!(parameters.contains("Integer(2)"));
+    }
+
+    #[test]
+    fn first_ingest_archives_nothing() {
+        let db = RecordingDatabase {
+            version: None,
+            statements: Default::default(),
+        };
+        archive_replaced_paragraph(&db, "https://example.com").unwrap();
+        assert!(db
+            .statements
+            .borrow()
+            .iter()
+            .all(|(query, _)| !query.contains("paragraph_versions")));
+    }
+
+    #[test]
+    fn mmr_rerank_demotes_redundant_items() {
+        let items = vec![
+            ("a", 0.9, vec![1.0, 0.0]),
+            ("a-copy", 0.85, vec![1.0, 0.0]),
+            ("b", 0.5, vec![0.0, 1.0]),
+        ];
+        let reranked = mmr_rerank(items, 0.5, |item| item.1, |item| &item.2);
+        let order: Vec<&str> = reranked.iter().map(|(item, _)| item.0).collect();
+        assert_eq!(order, vec!["a", "b", "a-copy"]);
+        assert!(reranked.iter().all(|(_, score)| score.is_some()));
+    }
 }
@@ -0,0 +1,83 @@
+//! Strategies for splitting a text into the segments stored by
+//! `POST /embeddings/segment`. Whitespace is recognised with
+//! `char::is_whitespace`, so non-breaking and other Unicode spaces count too.
+
+/// Splits after `.`, `?` or `!` when followed by whitespace and a capital
+/// letter, so abbreviations and decimals like "e.g. this" or "3.5" stay whole.
+pub fn split_by_sentence(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        if !matches!(c, '.' | '?' | '!') {
+            continue;
+        }
+        let end = index + c.len_utf8();
+        let mut whitespace = false;
+        while let Some(&(_, next)) = chars.peek() {
+            if !next.is_whitespace() {
+                break;
+            }
+            whitespace = true;
+            chars.next();
+        }
+        if let Some(&(next_start, next)) = chars.peek() {
+            if whitespace && next.is_uppercase() {
+                sentences.push(&text[start..end]);
+                start = next_start;
+            }
+        }
+    }
+    sentences.push(&text[start..]);
+
+    sentences
+        .into_iter()
+        .map(str::trim)
+        .filter(|sentence| !sentence.is_empty())
+        .collect()
+}
+
+/// Splits on blank lines, including lines holding nothing but whitespace.
+pub fn split_by_paragraph(text: &str) -> Vec<&str> {
+    let mut paragraphs = Vec::new();
+    let mut start = 0;
+    let mut offset = 0;
+
+    for line in text.split_inclusive('\n') {
+        if line.trim().is_empty() {
+            paragraphs.push(&text[start..offset]);
+            start = offset + line.len();
+        }
+        offset += line.len();
+    }
+    paragraphs.push(&text[start..]);
+
+    paragraphs
+        .into_iter()
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect()
+}
+
+/// Splits into windows of `window` words, each starting `window - overlap`
+/// words after the previous one. The last window may be shorter.
+pub fn split_by_words(text: &str, window: usize, overlap: usize) -> Vec<String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    if window == 0 || words.is_empty() {
+        return vec![];
+    }
+    let step = window.saturating_sub(overlap).max(1);
+
+    let mut windows = Vec::new();
+    let mut start = 0;
+    loop {
+        let end = (start + window).min(words.len());
+        windows.push(words[start..end].join(" "));
+        if end == words.len() {
+            break;
+        }
+        start += step;
+    }
+    windows
+}
@@ -0,0 +1,14 @@
+//! Element-wise arithmetic on embeddings, for `POST /embeddings/vector-math`.
+//! Vectors of different lengths are combined up to the shorter one.
+
+pub fn add(a: &[f32], b: &[f32]) -> Vec<f32> {
+    a.iter().zip(b).map(|(x, y)| x + y).collect()
+}
+
+pub fn subtract(a: &[f32], b: &[f32]) -> Vec<f32> {
+    a.iter().zip(b).map(|(x, y)| x - y).collect()
+}
+
+pub fn scale(v: &[f32], s: f32) -> Vec<f32> {
+    v.iter().map(|x| x * s).collect()
+}